@@ -1,12 +1,18 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    /// Listening address
-    #[arg(short, long, default_value = "0.0.0.0:8080")]
-    pub(crate) address: String,
-    /// Salt for hashing IP addresses
+    /// Configuration file in TOML format
+    #[arg(short, long)]
+    pub(crate) config: Option<PathBuf>,
+    /// Listening address like 0.0.0.0:8080 or [::]:8080, overrides the configuration. Repeat
+    /// it or separate addresses with commas to listen on several
+    #[arg(short, long, value_delimiter = ',')]
+    pub(crate) address: Vec<String>,
+    /// Salt for hashing IP addresses, overrides the configuration
     #[arg(short, long)]
-    pub(crate) ip_hash_salt: String,
+    pub(crate) ip_hash_salt: Option<String>,
 }
@@ -0,0 +1,360 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use failure::{format_err, Error};
+use futures_util::future::{self, BoxFuture};
+use futures_util::stream::{self, BoxStream};
+use futures_util::StreamExt;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::error::{ErrorCode, SignallerError};
+use crate::metrics;
+use crate::outbox::OutboxReceiver;
+use crate::redis::Redis;
+use crate::signaller_message::SignallerMessage;
+use crate::state::StateType;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// How long a node owns a room without renewing its claim, so that the rooms of a node that
+/// died become free again.
+const ROOM_CLAIM_TTL: Duration = Duration::from_secs(30);
+/// How often a node renews the claims on its rooms.
+const RENEW_INTERVAL: Duration = Duration::from_secs(10);
+/// Number of messages waiting to be published before relaying fails.
+const OUTGOING_QUEUE_DEPTH: usize = 4096;
+const NODE_ID_LEN: usize = 12;
+
+/// Records which node owns each room, so that viewers connected to other nodes can find it.
+pub trait RoomDirectory: Send + Sync {
+    /// Makes `node` the owner of `room` for `ttl` unless another node owns it, returning
+    /// whether `node` owns it now. Claiming a room again renews the claim.
+    fn claim<'a>(
+        &'a self,
+        room: &'a str,
+        node: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<bool>>;
+
+    fn owner<'a>(&'a self, room: &'a str) -> BoxFuture<'a, Result<Option<String>>>;
+
+    /// Gives up the claim of `node` on `room`, if it still has one.
+    fn release<'a>(&'a self, room: &'a str, node: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Carries messages between nodes. Delivery is best effort and in order between two nodes.
+pub trait MessageBus: Send + Sync {
+    fn publish<'a>(&'a self, node: &'a str, message: &'a BusMessage) -> BoxFuture<'a, Result<()>>;
+
+    /// The messages published to `node`, for as long as the stream is polled.
+    fn subscribe(&self, node: &str) -> BoxStream<'static, BusMessage>;
+}
+
+/// What nodes tell each other about the viewers relayed between them. The node a viewer is
+/// connected to relays what it sends to the node owning its room, which runs the session and
+/// relays back what the viewer is sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BusMessage {
+    /// A viewer connected to `node` wants to join a room owned by the receiver. `join_id`
+    /// tells this join apart from earlier ones of the same viewer.
+    Join {
+        node: String,
+        join_id: u64,
+        from: String,
+        room: String,
+        password: Option<String>,
+        protocol_version: u32,
+        hashed_ip: Option<String>,
+    },
+    /// The receiver owns the room a viewer connected to `node` asked to join and got its join.
+    JoinReceived {
+        node: String,
+        join_id: u64,
+        peer: String,
+    },
+    /// A message a viewer connected to `node` sent to its session on the receiver.
+    Relay {
+        node: String,
+        from: String,
+        message: SignallerMessage,
+    },
+    /// A websocket message for a viewer connected to the receiver.
+    Deliver { to: String, payload: String },
+    /// The viewer is no longer part of the room `node` owns.
+    Left {
+        node: String,
+        join_id: u64,
+        peer: String,
+    },
+}
+
+impl BusMessage {
+    fn kind(&self) -> &'static str {
+        match self {
+            BusMessage::Join { .. } => "join",
+            BusMessage::JoinReceived { .. } => "join_received",
+            BusMessage::Relay { .. } => "relay",
+            BusMessage::Deliver { .. } => "deliver",
+            BusMessage::Left { .. } => "left",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Identifies this node to the others. A random one is picked at startup if it is not
+    /// set.
+    #[serde(default)]
+    pub node_id: Option<String>,
+
+    #[serde(default)]
+    pub backend: ClusterBackend,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClusterBackend {
+    /// Keeps the directory and bus in the process, for a single node.
+    #[default]
+    Memory,
+    /// Shares them through a Redis server, or anything else speaking its protocol.
+    Redis { url: String },
+}
+
+/// The rooms and bus of a single node.
+#[derive(Default)]
+pub struct InMemory {
+    owners: DashMap<String, String>,
+    subscribers: DashMap<String, mpsc::UnboundedSender<BusMessage>>,
+}
+
+impl RoomDirectory for InMemory {
+    fn claim<'a>(
+        &'a self,
+        room: &'a str,
+        node: &'a str,
+        _ttl: Duration,
+    ) -> BoxFuture<'a, Result<bool>> {
+        let owner = self
+            .owners
+            .entry(room.to_string())
+            .or_insert_with(|| node.to_string());
+        Box::pin(future::ready(Ok(owner.value() == node)))
+    }
+
+    fn owner<'a>(&'a self, room: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        let owner = self.owners.get(room).map(|owner| owner.value().clone());
+        Box::pin(future::ready(Ok(owner)))
+    }
+
+    fn release<'a>(&'a self, room: &'a str, node: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.owners.remove_if(room, |_, owner| owner == node);
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+impl MessageBus for InMemory {
+    fn publish<'a>(&'a self, node: &'a str, message: &'a BusMessage) -> BoxFuture<'a, Result<()>> {
+        let result = match self.subscribers.get(node) {
+            Some(subscriber) => subscriber
+                .send(message.clone())
+                .map_err(|_| format_err!("node {} stopped listening", node)),
+            None => Err(format_err!("node {} is not listening", node)),
+        };
+        Box::pin(future::ready(result))
+    }
+
+    fn subscribe(&self, node: &str) -> BoxStream<'static, BusMessage> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        self.subscribers.insert(node.to_string(), tx);
+        stream::poll_fn(move |cx| rx.poll_recv(cx)).boxed()
+    }
+}
+
+/// This node's view of the cluster: who it is, and how it reaches the other nodes.
+pub struct Cluster {
+    pub node_id: String,
+    directory: Arc<dyn RoomDirectory>,
+    bus: Arc<dyn MessageBus>,
+    /// Messages from clients wait here to be published in the order they were sent.
+    outgoing: mpsc::Sender<(String, BusMessage)>,
+    outgoing_rx: Mutex<Option<mpsc::Receiver<(String, BusMessage)>>>,
+    next_join_id: AtomicU64,
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig) -> Self {
+        let (directory, bus): (Arc<dyn RoomDirectory>, Arc<dyn MessageBus>) = match &config.backend
+        {
+            ClusterBackend::Memory => {
+                let memory = Arc::new(InMemory::default());
+                (memory.clone(), memory)
+            }
+            ClusterBackend::Redis { url } => {
+                let redis = Arc::new(Redis::new(url).expect("redis url was validated"));
+                (redis.clone(), redis)
+            }
+        };
+        let node_id = config
+            .node_id
+            .clone()
+            .unwrap_or_else(|| crate::generate_peer_id(NODE_ID_LEN));
+        info!("Running as node {}", node_id);
+        let (outgoing, outgoing_rx) = mpsc::channel(OUTGOING_QUEUE_DEPTH);
+        Cluster {
+            node_id,
+            directory,
+            bus,
+            outgoing,
+            outgoing_rx: Mutex::new(Some(outgoing_rx)),
+            next_join_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Claims a new room for this node.
+    pub async fn claim(&self, room: &str) -> Result<bool> {
+        self.directory
+            .claim(room, &self.node_id, ROOM_CLAIM_TTL)
+            .await
+            .map_err(directory_unavailable)
+    }
+
+    /// The node owning a room, if that is not this one.
+    pub async fn remote_owner(&self, room: &str) -> Result<Option<String>> {
+        let owner = self
+            .directory
+            .owner(room)
+            .await
+            .map_err(directory_unavailable)?;
+        Ok(owner.filter(|owner| *owner != self.node_id))
+    }
+
+    /// Gives up a room in the background once its session ended.
+    pub fn release(self: &Arc<Self>, room: String) {
+        let cluster = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = cluster.directory.release(&room, &cluster.node_id).await {
+                warn!("Failed to release room {}: {}", room, e);
+                metrics::NUM_BUS_ERRORS.inc();
+            }
+        });
+    }
+
+    pub fn next_join_id(&self) -> u64 {
+        self.next_join_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Queues a message for another node.
+    pub fn send(&self, node: &str, message: BusMessage) -> Result<()> {
+        self.outgoing
+            .try_send((node.to_string(), message))
+            .map_err(|e| format_err!("cannot relay to node {}: {}", node, e))
+    }
+
+    /// Publishes a message to another node, returning whether that worked.
+    async fn publish(&self, node: &str, message: &BusMessage) -> bool {
+        match self.bus.publish(node, message).await {
+            Ok(()) => {
+                metrics::NUM_BUS_MESSAGES
+                    .with_label_values(&[message.kind(), "sent"])
+                    .inc();
+                true
+            }
+            Err(e) => {
+                error!("Failed to publish to node {}: {}", node, e);
+                metrics::NUM_BUS_ERRORS.inc();
+                false
+            }
+        }
+    }
+
+    /// The messages other nodes send to this one.
+    pub fn incoming(&self) -> BoxStream<'static, BusMessage> {
+        let incoming = self.bus.subscribe(&self.node_id);
+        incoming
+            .inspect(|message| {
+                metrics::NUM_BUS_MESSAGES
+                    .with_label_values(&[message.kind(), "received"])
+                    .inc()
+            })
+            .boxed()
+    }
+
+    /// Relays what is queued for a viewer of another node until its outbox closes, then lets
+    /// that node know the viewer left.
+    pub async fn relay(
+        self: Arc<Self>,
+        mut receiver: OutboxReceiver,
+        node: String,
+        join_id: u64,
+        peer: String,
+    ) {
+        while let Some(message) = receiver.next().await {
+            // Pings and close frames are up to the node the viewer is connected to.
+            let Ok(payload) = message.to_str() else {
+                continue;
+            };
+            let deliver = BusMessage::Deliver {
+                to: peer.clone(),
+                payload: payload.to_string(),
+            };
+            self.publish(&node, &deliver).await;
+        }
+        let left = BusMessage::Left {
+            node: self.node_id.clone(),
+            join_id,
+            peer,
+        };
+        self.publish(&node, &left).await;
+    }
+}
+
+/// Logs a failure to reach the room directory, which clients only learn is unavailable.
+fn directory_unavailable(e: Error) -> Error {
+    error!("Failed to reach the room directory: {}", e);
+    metrics::NUM_BUS_ERRORS.inc();
+    SignallerError::new(ErrorCode::Internal, "the room directory is unavailable").into()
+}
+
+/// Publishes the queued messages, for as long as the server runs. Viewers whose join could
+/// not be published are declined.
+pub async fn publish_outgoing(state: StateType) {
+    let Some(mut outgoing) = state.cluster.outgoing_rx.lock().unwrap().take() else {
+        return;
+    };
+    while let Some((node, message)) = outgoing.recv().await {
+        if state.cluster.publish(&node, &message).await {
+            continue;
+        }
+        if let BusMessage::Join { join_id, from, .. } = message {
+            state.decline_relayed(&node, join_id, &from);
+        }
+    }
+}
+
+/// Renews the claims on the rooms of this node, for as long as the server runs. Rooms another
+/// node took over are closed, so that only one node serves each room.
+pub async fn renew_claims(state: StateType) {
+    let mut interval = tokio::time::interval(RENEW_INTERVAL);
+    loop {
+        interval.tick().await;
+        for room in state.room_ids() {
+            match state.cluster.claim(&room).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("Room {} is claimed by another node, closing it", room);
+                    state
+                        .close_room(&room)
+                        .unwrap_or_else(|e| info!("Error closing room {}: {}", room, e));
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
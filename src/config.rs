@@ -1,37 +1,545 @@
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use serde::{Deserialize, Serialize};
+use argon2::password_hash::SaltString;
+use failure::{format_err, Error};
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::args::Args;
+
+use crate::cluster::{ClusterBackend, ClusterConfig};
+use crate::ice_servers::{
+    default_coturn_ttl_secs, default_coturn_username, ChainMode, IceProviderConfig,
+};
+use crate::outbox::BackpressurePolicy;
+use crate::rate_limit::{default_rate_limits, MessageRateLimit};
+use crate::redis;
+use crate::signaller_message::RTCIceServer;
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0:8080";
+const DEFAULT_LOG_LEVEL: &str = "debug";
+const DEFAULT_MAX_VIEWERS_PER_ROOM: usize = 16;
+const DEFAULT_RESUME_GRACE_PERIOD_SECS: u64 = 30;
+const DEFAULT_PING_INTERVAL_SECS: u64 = 15;
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 45;
+const DEFAULT_SEND_QUEUE_DEPTH: usize = 256;
+const DEFAULT_MAX_RATE_LIMIT_VIOLATIONS: u32 = 20;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_SDP_LENGTH: usize = 32 * 1024;
+const DEFAULT_MAX_STRING_LENGTH: usize = 256;
+const DEFAULT_MAX_ICE_CANDIDATES_PER_PAIR: usize = 1000;
+const DEFAULT_DRAIN_PERIOD_SECS: u64 = 25;
+const DEFAULT_RECONNECT_AFTER_SECS: u64 = 5;
+const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 30;
+const DEFAULT_SNAPSHOT_RESTORE_WINDOW_SECS: u64 = 120;
+
+/// Settings whose values must not end up in the logs.
+const SECRET_SETTINGS: &[&str] = &["ip_hash_salt", "twilio_account_sid", "twilio_auth_token"];
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Addresses the server listens on, like `0.0.0.0:8080` or `[::]:8080`. A single address
+    /// can be given as `address`.
+    #[serde(
+        default = "default_addresses",
+        alias = "address",
+        deserialize_with = "one_or_many"
+    )]
+    pub addresses: Vec<String>,
+
+    /// Base64 salt for hashing the IP addresses of clients. Required.
+    #[serde(default)]
+    pub ip_hash_salt: Option<String>,
+
+    /// Most verbose level that is logged, unless `RUST_LOG` is more restrictive.
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+
+    /// Whether Prometheus metrics are served on `/metrics`.
+    #[serde(default = "default_metrics_enabled")]
+    pub metrics_enabled: bool,
+
     #[serde()]
     pub twilio_account_sid: Option<String>,
 
     #[serde()]
     pub twilio_auth_token: Option<String>,
+
+    /// Where ICE servers come from, asked in order. Twilio credentials set above add a Twilio
+    /// provider after these.
+    #[serde(default)]
+    pub ice_providers: Vec<IceProviderConfig>,
+
+    #[serde(default = "default_ice_provider_mode")]
+    pub ice_provider_mode: ChainMode,
+
+    /// Upper bound for the number of viewers in a room. Sharers may pick a lower limit.
+    #[serde(default = "default_max_viewers_per_room")]
+    pub max_viewers_per_room: usize,
+
+    /// How long a session is kept for a disconnected sharer to resume it.
+    #[serde(default = "default_resume_grace_period_secs")]
+    pub resume_grace_period_secs: u64,
+
+    /// How often the server pings every connection.
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+
+    /// Connections that send nothing, not even a pong, for this long are closed.
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+
+    /// Number of messages queued for a connection before the backpressure policy kicks in.
+    #[serde(default = "default_send_queue_depth")]
+    pub send_queue_depth: usize,
+
+    #[serde(default = "default_backpressure_policy")]
+    pub backpressure_policy: BackpressurePolicy,
+
+    /// Rate limits keyed by message type. Setting this replaces the defaults entirely.
+    #[serde(default = "default_rate_limits")]
+    pub rate_limits: HashMap<String, MessageRateLimit>,
+
+    /// Connections are closed once this many of their messages were refused by a rate limit.
+    #[serde(default = "default_max_rate_limit_violations")]
+    pub max_rate_limit_violations: u32,
+
+    /// Largest websocket message, in bytes, the server reads. Bigger ones are answered with a
+    /// `payload_too_large` error and close the connection.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+
+    #[serde(default = "default_max_sdp_length")]
+    pub max_sdp_length: usize,
+
+    /// Longest ids, reasons, passwords and other strings accepted from clients, in bytes.
+    #[serde(default = "default_max_string_length")]
+    pub max_string_length: usize,
+
+    /// Number of ICE candidates two peers of a session may exchange. The count starts over
+    /// when either of them leaves.
+    #[serde(default = "default_max_ice_candidates_per_pair")]
+    pub max_ice_candidates_per_pair: usize,
+
+    /// How long sessions may continue after SIGTERM or SIGINT before their connections are
+    /// closed.
+    #[serde(default = "default_drain_period_secs")]
+    pub drain_period_secs: u64,
+
+    /// Seconds clients are told to wait before reconnecting when the server shuts down.
+    #[serde(default = "default_reconnect_after_secs")]
+    pub reconnect_after_secs: u64,
+
+    /// File the sessions are saved to periodically and on shutdown, and restored from at
+    /// startup. Sessions are not saved if this is not set.
+    #[serde(default)]
+    pub snapshot_path: Option<PathBuf>,
+
+    #[serde(default = "default_snapshot_interval_secs")]
+    pub snapshot_interval_secs: u64,
+
+    /// How long after a snapshot was taken its sharers and viewers can still resume.
+    #[serde(default = "default_snapshot_restore_window_secs")]
+    pub snapshot_restore_window_secs: u64,
+
+    /// How rooms are shared with the other nodes behind the same load balancer. By default
+    /// this node keeps its rooms to itself.
+    #[serde(default)]
+    pub cluster: ClusterConfig,
+}
+
+fn default_addresses() -> Vec<String> {
+    vec![DEFAULT_ADDRESS.to_string()]
+}
+
+fn default_log_level() -> String {
+    DEFAULT_LOG_LEVEL.to_string()
+}
+
+fn default_metrics_enabled() -> bool {
+    true
+}
+
+fn default_ice_provider_mode() -> ChainMode {
+    ChainMode::All
+}
+
+fn default_max_viewers_per_room() -> usize {
+    DEFAULT_MAX_VIEWERS_PER_ROOM
+}
+
+fn default_resume_grace_period_secs() -> u64 {
+    DEFAULT_RESUME_GRACE_PERIOD_SECS
+}
+
+fn default_ping_interval_secs() -> u64 {
+    DEFAULT_PING_INTERVAL_SECS
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    DEFAULT_IDLE_TIMEOUT_SECS
+}
+
+fn default_send_queue_depth() -> usize {
+    DEFAULT_SEND_QUEUE_DEPTH
 }
 
-#[allow(dead_code)]
-pub fn load(path: &Path) -> Result<Config, failure::Error> {
-    // create a new file if it does not exist
-    if !path.exists() {
-        let mut file = File::create(path)?;
-        let config = toml::from_str::<Config>("")?;
-        file.write_all(toml::to_string(&config)?.as_ref())?;
-        return Ok(config);
+fn default_backpressure_policy() -> BackpressurePolicy {
+    BackpressurePolicy::DropIce
+}
+
+fn default_max_rate_limit_violations() -> u32 {
+    DEFAULT_MAX_RATE_LIMIT_VIOLATIONS
+}
+
+fn default_max_message_size() -> usize {
+    DEFAULT_MAX_MESSAGE_SIZE
+}
+
+fn default_max_sdp_length() -> usize {
+    DEFAULT_MAX_SDP_LENGTH
+}
+
+fn default_max_string_length() -> usize {
+    DEFAULT_MAX_STRING_LENGTH
+}
+
+fn default_max_ice_candidates_per_pair() -> usize {
+    DEFAULT_MAX_ICE_CANDIDATES_PER_PAIR
+}
+
+fn default_drain_period_secs() -> u64 {
+    DEFAULT_DRAIN_PERIOD_SECS
+}
+
+fn default_reconnect_after_secs() -> u64 {
+    DEFAULT_RECONNECT_AFTER_SECS
+}
+
+fn default_snapshot_interval_secs() -> u64 {
+    DEFAULT_SNAPSHOT_INTERVAL_SECS
+}
+
+fn default_snapshot_restore_window_secs() -> u64 {
+    DEFAULT_SNAPSHOT_RESTORE_WINDOW_SECS
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        toml::from_str("").expect("every setting has a default")
+    }
+}
+
+impl Config {
+    pub fn socket_addrs(&self) -> Result<Vec<SocketAddr>, Error> {
+        if self.addresses.is_empty() {
+            return Err(format_err!("addresses cannot be empty"));
+        }
+        let mut socket_addrs: Vec<SocketAddr> = vec![];
+        for address in &self.addresses {
+            let socket_addr = address.parse().map_err(|_| {
+                format_err!(
+                    "address {:?} is not valid, expected an IP and a port like 0.0.0.0:8080 \
+                     or [::]:8080",
+                    address
+                )
+            })?;
+            if socket_addrs.contains(&socket_addr) {
+                return Err(format_err!("address {} is listed twice", socket_addr));
+            }
+            socket_addrs.push(socket_addr);
+        }
+        Ok(socket_addrs)
+    }
+
+    pub fn log_level(&self) -> Result<LevelFilter, Error> {
+        self.log_level
+            .parse()
+            .map_err(|_| format_err!("log_level {:?} is not a log level", self.log_level))
+    }
+
+    /// The configured ICE providers, followed by Twilio if its credentials are set.
+    pub fn all_ice_providers(&self) -> Vec<IceProviderConfig> {
+        let mut providers = self.ice_providers.clone();
+        if let (Some(account_sid), Some(auth_token)) =
+            (&self.twilio_account_sid, &self.twilio_auth_token)
+        {
+            providers.push(IceProviderConfig::Twilio {
+                account_sid: account_sid.clone(),
+                auth_token: auth_token.clone(),
+            });
+        }
+        providers
     }
 
-    let mut file = File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    Ok(toml::from_str(&contents)?)
+    /// The settings that differ in `other`, each with a description of the change for the
+    /// logs. Secrets and lists are only named.
+    pub fn diff(&self, other: &Config) -> Vec<(String, String)> {
+        let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+            (serde_json::to_value(self), serde_json::to_value(other))
+        else {
+            return vec![];
+        };
+        new.into_iter()
+            .filter(|(name, value)| old.get(name) != Some(value))
+            .map(|(name, value)| {
+                let old = old.get(&name).unwrap_or(&Value::Null);
+                let description = if SECRET_SETTINGS.contains(&name.as_str())
+                    || value.is_object()
+                    || value.is_array()
+                {
+                    "changed".to_string()
+                } else {
+                    format!("{} -> {}", old, value)
+                };
+                (name, description)
+            })
+            .collect()
+    }
+
+    /// Overrides settings with the environment variables that are set.
+    fn apply_env(&mut self) -> Result<(), Error> {
+        if let Ok(addresses) = std::env::var("LISTEN_ADDRESS") {
+            self.addresses = split_list(&addresses);
+        }
+        if let Ok(salt) = std::env::var("IP_HASH_SALT") {
+            self.ip_hash_salt = Some(salt);
+        }
+        env_override("LOG_LEVEL", &mut self.log_level)?;
+        env_override("METRICS_ENABLED", &mut self.metrics_enabled)?;
+        if let Ok(account_sid) = std::env::var("TWILIO_ACCOUNT_SID") {
+            self.twilio_account_sid = Some(account_sid);
+        }
+        if let Ok(auth_token) = std::env::var("TWILIO_AUTH_TOKEN") {
+            self.twilio_auth_token = Some(auth_token);
+        }
+        self.ice_providers.extend(ice_providers_from_env()?);
+        env_override("ICE_PROVIDER_MODE", &mut self.ice_provider_mode)?;
+        env_override("MAX_VIEWERS_PER_ROOM", &mut self.max_viewers_per_room)?;
+        env_override(
+            "RESUME_GRACE_PERIOD_SECS",
+            &mut self.resume_grace_period_secs,
+        )?;
+        env_override("PING_INTERVAL_SECS", &mut self.ping_interval_secs)?;
+        env_override("IDLE_TIMEOUT_SECS", &mut self.idle_timeout_secs)?;
+        env_override("SEND_QUEUE_DEPTH", &mut self.send_queue_depth)?;
+        env_override("BACKPRESSURE_POLICY", &mut self.backpressure_policy)?;
+        env_override(
+            "MAX_RATE_LIMIT_VIOLATIONS",
+            &mut self.max_rate_limit_violations,
+        )?;
+        env_override("MAX_MESSAGE_SIZE", &mut self.max_message_size)?;
+        env_override("MAX_SDP_LENGTH", &mut self.max_sdp_length)?;
+        env_override("MAX_STRING_LENGTH", &mut self.max_string_length)?;
+        env_override(
+            "MAX_ICE_CANDIDATES_PER_PAIR",
+            &mut self.max_ice_candidates_per_pair,
+        )?;
+        env_override("DRAIN_PERIOD_SECS", &mut self.drain_period_secs)?;
+        env_override("RECONNECT_AFTER_SECS", &mut self.reconnect_after_secs)?;
+        if let Ok(path) = std::env::var("SNAPSHOT_PATH") {
+            self.snapshot_path = Some(path.into());
+        }
+        env_override("SNAPSHOT_INTERVAL_SECS", &mut self.snapshot_interval_secs)?;
+        env_override(
+            "SNAPSHOT_RESTORE_WINDOW_SECS",
+            &mut self.snapshot_restore_window_secs,
+        )?;
+        if let Ok(node_id) = std::env::var("CLUSTER_NODE_ID") {
+            self.cluster.node_id = Some(node_id);
+        }
+        if let Ok(url) = std::env::var("REDIS_URL") {
+            self.cluster.backend = ClusterBackend::Redis { url };
+        }
+        Ok(())
+    }
+
+    /// Checks the settings that the server could not run with, or that would be sure to
+    /// misbehave.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.socket_addrs()?;
+        self.log_level()?;
+        let salt = self.ip_hash_salt.as_deref().ok_or_else(|| {
+            format_err!(
+                "ip_hash_salt is not set, use --ip-hash-salt, IP_HASH_SALT or the configuration file"
+            )
+        })?;
+        SaltString::from_b64(salt)
+            .map_err(|e| format_err!("ip_hash_salt is not a valid base64 salt: {}", e))?;
+        if self.twilio_account_sid.is_some() != self.twilio_auth_token.is_some() {
+            return Err(format_err!(
+                "twilio_account_sid and twilio_auth_token must be set together"
+            ));
+        }
+        for provider in &self.ice_providers {
+            match provider {
+                IceProviderConfig::Static { servers } => {
+                    if servers.iter().any(|server| server.urls.is_empty()) {
+                        return Err(format_err!("static ICE servers need at least one url"));
+                    }
+                }
+                IceProviderConfig::Coturn {
+                    urls,
+                    secret,
+                    ttl_secs,
+                    ..
+                } => {
+                    if urls.is_empty() || secret.is_empty() || *ttl_secs == 0 {
+                        return Err(format_err!(
+                            "coturn ICE providers need urls, a secret and a ttl_secs above 0"
+                        ));
+                    }
+                }
+                IceProviderConfig::Twilio { .. } => {}
+            }
+        }
+        if self.max_viewers_per_room == 0 {
+            return Err(format_err!("max_viewers_per_room must be at least 1"));
+        }
+        if self.ping_interval_secs == 0 {
+            return Err(format_err!("ping_interval_secs must be at least 1"));
+        }
+        if self.idle_timeout_secs <= self.ping_interval_secs {
+            return Err(format_err!(
+                "idle_timeout_secs must be longer than ping_interval_secs, or every connection \
+                 would be closed"
+            ));
+        }
+        if self.snapshot_interval_secs == 0 {
+            return Err(format_err!("snapshot_interval_secs must be at least 1"));
+        }
+        if self.send_queue_depth == 0 {
+            return Err(format_err!("send_queue_depth must be at least 1"));
+        }
+        for (kind, limit) in &self.rate_limits {
+            for rate in [limit.per_connection, limit.per_ip].iter().flatten() {
+                if rate.burst < 1.0 || !rate.per_sec.is_finite() || rate.per_sec < 0.0 {
+                    return Err(format_err!(
+                        "rate limit for {} needs a burst of at least 1 and a per_sec of at least 0",
+                        kind
+                    ));
+                }
+            }
+        }
+        if self
+            .cluster
+            .node_id
+            .as_ref()
+            .is_some_and(|id| id.is_empty())
+        {
+            return Err(format_err!("cluster node_id cannot be empty"));
+        }
+        if let ClusterBackend::Redis { url } = &self.cluster.backend {
+            redis::check_url(url)?;
+        }
+        if self.max_sdp_length > self.max_message_size {
+            return Err(format_err!(
+                "max_sdp_length cannot be larger than max_message_size"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reads a configuration file. Settings it leaves out keep their defaults.
+pub fn load(path: &Path) -> Result<Config, Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format_err!("cannot read {}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| format_err!("invalid {}: {}", path.display(), e))
+}
+
+/// The configuration file given on the command line, if any, overridden by environment
+/// variables and then by command line arguments.
+pub fn build(args: &Args) -> Result<Config, Error> {
+    let mut config = match &args.config {
+        Some(path) => load(path)?,
+        None => Config::default(),
+    };
+    config.apply_env()?;
+    if !args.address.is_empty() {
+        config.addresses = args.address.clone();
+    }
+    if let Some(salt) = &args.ip_hash_salt {
+        config.ip_hash_salt = Some(salt.clone());
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+fn env_override<T>(name: &str, value: &mut T) -> Result<(), Error>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Ok(v) = std::env::var(name) {
+        *value = v
+            .parse()
+            .map_err(|e| format_err!("{} is not valid: {}", name, e))?;
+    }
+    Ok(())
+}
+
+/// A single value where a list is expected.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for Vec<String> {
+    fn from(value: OneOrMany) -> Self {
+        match value {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+/// Accepts a single value where a list is expected.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(OneOrMany::deserialize(deserializer)?.into())
 }
 
-pub fn from_env() -> Config {
-    Config {
-        twilio_account_sid: std::env::var("TWILIO_ACCOUNT_SID").ok(),
-        twilio_auth_token: std::env::var("TWILIO_AUTH_TOKEN").ok(),
+/// The values of a comma separated environment variable.
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|value| value.trim().to_string())
+        .collect()
+}
+
+/// A static list from `ICE_SERVER_URLS` and a coturn server from `COTURN_URLS` and
+/// `COTURN_SECRET`, both comma separated.
+fn ice_providers_from_env() -> Result<Vec<IceProviderConfig>, Error> {
+    let urls = |name| -> Option<Vec<String>> { Some(split_list(&std::env::var(name).ok()?)) };
+    let mut providers = vec![];
+    if let Some(urls) = urls("ICE_SERVER_URLS") {
+        providers.push(IceProviderConfig::Static {
+            servers: vec![RTCIceServer {
+                urls,
+                ..Default::default()
+            }],
+        });
+    }
+    if let (Some(urls), Ok(secret)) = (urls("COTURN_URLS"), std::env::var("COTURN_SECRET")) {
+        let mut ttl_secs = default_coturn_ttl_secs();
+        env_override("COTURN_TTL_SECS", &mut ttl_secs)?;
+        providers.push(IceProviderConfig::Coturn {
+            urls,
+            secret,
+            ttl_secs,
+            username: default_coturn_username(),
+        });
     }
+    Ok(providers)
 }
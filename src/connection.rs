@@ -0,0 +1,190 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use log::info;
+use warp::ws::Message;
+
+use crate::error::{ErrorCode, SignallerError};
+use crate::outbox::Outbox;
+use crate::peer::{generate_resume_token, Peer, PeerType};
+use crate::rate_limit::RateLimiter;
+use crate::signaller_message::{
+    ClientKind, Envelope, SignallerMessage, LEGACY_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+
+/// What the shared state knows about an open websocket, whether or not it joined a session.
+pub struct ConnectionHandle {
+    pub sender: Outbox,
+    /// When anything, including a pong, was last received on the connection.
+    pub last_seen: Instant,
+}
+
+/// Per-websocket state that lives for as long as the connection is open.
+pub struct Connection {
+    /// The server assigned id of this connection. It is replaced by the room id once the
+    /// connection starts a session, since sharers are addressed by their room.
+    pub peer_id: String,
+    pub sender: Outbox,
+    pub socket_addr: SocketAddr,
+    /// The salted hash of the client's real IP.
+    pub hashed_ip: String,
+    /// The negotiated protocol version, `None` until the first message arrives.
+    pub protocol_version: Option<u32>,
+    pub client_kind: Option<ClientKind>,
+    pub rate_limiter: RateLimiter,
+    /// Number of messages refused for exceeding a rate limit.
+    pub rate_limit_violations: u32,
+}
+
+impl Connection {
+    pub fn new(
+        peer_id: String,
+        sender: Outbox,
+        socket_addr: SocketAddr,
+        hashed_ip: String,
+    ) -> Self {
+        Connection {
+            peer_id,
+            sender,
+            socket_addr,
+            hashed_ip,
+            protocol_version: None,
+            client_kind: None,
+            rate_limiter: Default::default(),
+            rate_limit_violations: 0,
+        }
+    }
+
+    /// Negotiates the protocol version from a `Hello`, downgrading clients that are
+    /// newer than this server and refusing clients that are too old.
+    pub fn negotiate(
+        &mut self,
+        client_version: u32,
+        client_kind: ClientKind,
+    ) -> Result<u32, SignallerError> {
+        if self.protocol_version.is_some() {
+            return Err(SignallerError::new(
+                ErrorCode::UnexpectedMessage,
+                "hello must be the first message on a connection",
+            ));
+        }
+        if client_version < MIN_PROTOCOL_VERSION {
+            return Err(SignallerError::new(
+                ErrorCode::UnsupportedProtocolVersion,
+                format!(
+                    "protocol version {} is not supported, minimum is {}",
+                    client_version, MIN_PROTOCOL_VERSION
+                ),
+            ));
+        }
+        let version = client_version.min(PROTOCOL_VERSION);
+        self.protocol_version = Some(version);
+        self.client_kind = Some(client_kind);
+        Ok(version)
+    }
+
+    /// Falls back to the legacy protocol for clients that never send a `Hello`.
+    pub fn ensure_negotiated(&mut self) -> Result<u32, SignallerError> {
+        if let Some(version) = self.protocol_version {
+            return Ok(version);
+        }
+        if LEGACY_PROTOCOL_VERSION < MIN_PROTOCOL_VERSION {
+            return Err(SignallerError::new(
+                ErrorCode::UnsupportedProtocolVersion,
+                "a hello message is required before any other message",
+            ));
+        }
+        self.protocol_version = Some(LEGACY_PROTOCOL_VERSION);
+        Ok(LEGACY_PROTOCOL_VERSION)
+    }
+
+    /// The peer this connection acts as once it is part of a session.
+    pub fn to_peer(&self, room: String, peer_type: PeerType) -> Peer {
+        Peer {
+            room,
+            sender: self.sender.clone(),
+            socket_addr: self.socket_addr,
+            peer_type,
+            protocol_version: self.protocol_version.unwrap_or(LEGACY_PROTOCOL_VERSION),
+            hashed_ip: Some(self.hashed_ip.clone()),
+            resume_token: generate_resume_token(),
+            away_until: None,
+            node: None,
+        }
+    }
+
+    /// Replaces the `from` field of a client message with the id bound to this connection.
+    /// Legacy clients pick their own ids, so theirs are overwritten; clients that negotiated
+    /// the current protocol know their id and are rejected if they claim another one.
+    pub fn bind_sender(&self, msg: &mut SignallerMessage) -> Result<(), SignallerError> {
+        let Some(from) = msg.sender_mut() else {
+            return Ok(());
+        };
+        if !from.is_empty() && *from != self.peer_id {
+            if self.protocol_version != Some(LEGACY_PROTOCOL_VERSION) {
+                return Err(SignallerError::new(
+                    ErrorCode::PeerIdMismatch,
+                    format!(
+                        "messages from this connection must be sent as {}",
+                        self.peer_id
+                    ),
+                ));
+            }
+            info!(
+                "{} claimed to be {}, overwriting with {}",
+                self.socket_addr, from, self.peer_id
+            );
+        }
+        *from = self.peer_id.clone();
+        Ok(())
+    }
+
+    /// Sends a reply to this connection, echoing the id of the request it answers.
+    pub fn reply(&self, message: SignallerMessage, request_id: Option<String>) {
+        let envelope = Envelope {
+            message,
+            request_id,
+        };
+        match serde_json::to_string(&envelope) {
+            Ok(payload) => self
+                .sender
+                .send(Message::text(payload))
+                .unwrap_or_else(|e| {
+                    info!("Error sending reply: {}", e);
+                }),
+            Err(e) => info!("Error serializing reply: {}", e),
+        }
+    }
+
+    /// Whether the client only knows the messages of the legacy protocol, which is assumed
+    /// until it negotiated another one.
+    pub fn is_legacy(&self) -> bool {
+        self.protocol_version
+            .is_none_or(|version| version <= LEGACY_PROTOCOL_VERSION)
+    }
+
+    /// Sends an error to this connection. Legacy clients know no errors, so it is only logged
+    /// for them.
+    pub fn reply_error(&self, error: &SignallerError, request_id: Option<String>) {
+        if self.is_legacy() {
+            info!("Not sending error to {}: {}", self.socket_addr, error);
+            return;
+        }
+        self.reply(
+            SignallerMessage::Error {
+                code: error.code,
+                message: error.message.clone(),
+            },
+            request_id,
+        );
+    }
+
+    /// Sends a close frame and stops delivering any further messages.
+    pub fn close(&self) {
+        self.sender.send(Message::close()).unwrap_or_else(|e| {
+            info!("Error sending close frame: {}", e);
+        });
+        self.sender.close();
+    }
+}
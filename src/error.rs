@@ -0,0 +1,68 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Machine readable error codes reported back to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MalformedMessage,
+    InvalidMessage,
+    UnexpectedMessage,
+    UnsupportedProtocolVersion,
+    InvalidSdp,
+    InvalidIceCandidate,
+    RoomNotFound,
+    PeerNotFound,
+    PeerIdMismatch,
+    AlreadyInSession,
+    NotInSession,
+    NotSharer,
+    PasswordRequired,
+    WrongPassword,
+    TooManyAttempts,
+    DeniedBySharer,
+    ApprovalTimeout,
+    Banned,
+    RoomFull,
+    InvalidResumeToken,
+    PeerAway,
+    RateLimited,
+    PayloadTooLarge,
+    TooManyCandidates,
+    ShuttingDown,
+    Internal,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(code)) => f.write_str(&code),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// An error that is reported to the client that caused it.
+#[derive(Debug)]
+pub struct SignallerError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl SignallerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        SignallerError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for SignallerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SignallerError {}
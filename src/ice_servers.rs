@@ -0,0 +1,288 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use futures_util::future::{self, BoxFuture};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use tokio::task::JoinHandle;
+
+use crate::signaller_message::{RTCIceCredentialType, RTCIceServer};
+use crate::twilio_helper::TwilioIceServers;
+
+const DEFAULT_COTURN_TTL_SECS: u64 = 86400;
+const DEFAULT_COTURN_USERNAME: &str = "signaller";
+
+/// ICE servers together with how long their credentials stay valid, if they expire.
+#[derive(Debug, Clone, Default)]
+pub struct IceServerList {
+    pub servers: Vec<RTCIceServer>,
+    pub ttl: Option<Duration>,
+}
+
+/// A source of the STUN and TURN servers handed out to clients.
+pub trait IceServerProvider: Send + Sync {
+    fn ice_servers(&self) -> BoxFuture<'_, IceServerList>;
+
+    /// Background work, like refreshing credentials, that runs for as long as the server does.
+    fn run(self: Arc<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(future::ready(()))
+    }
+}
+
+/// How the servers of several providers are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainMode {
+    /// Hand out the servers of every provider.
+    All,
+    /// Hand out the servers of the first provider that has any, so later ones act as fallbacks.
+    FirstAvailable,
+}
+
+impl FromStr for ChainMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(ChainMode::All),
+            "first_available" => Ok(ChainMode::FirstAvailable),
+            _ => Err(format!("unknown ICE provider mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IceProviderConfig {
+    /// A fixed list of servers.
+    Static { servers: Vec<RTCIceServer> },
+    /// A coturn server using the time-limited credentials of its REST API, signed with the
+    /// `static-auth-secret` it is configured with.
+    Coturn {
+        urls: Vec<String>,
+        secret: String,
+        #[serde(default = "default_coturn_ttl_secs")]
+        ttl_secs: u64,
+        #[serde(default = "default_coturn_username")]
+        username: String,
+    },
+    Twilio {
+        account_sid: String,
+        auth_token: String,
+    },
+}
+
+pub fn default_coturn_ttl_secs() -> u64 {
+    DEFAULT_COTURN_TTL_SECS
+}
+
+pub fn default_coturn_username() -> String {
+    DEFAULT_COTURN_USERNAME.to_string()
+}
+
+pub struct StaticIceServers {
+    servers: Vec<RTCIceServer>,
+}
+
+impl IceServerProvider for StaticIceServers {
+    fn ice_servers(&self) -> BoxFuture<'_, IceServerList> {
+        Box::pin(future::ready(IceServerList {
+            servers: self.servers.clone(),
+            ttl: None,
+        }))
+    }
+}
+
+pub struct CoturnIceServers {
+    urls: Vec<String>,
+    secret: String,
+    ttl: Duration,
+    username: String,
+}
+
+impl CoturnIceServers {
+    /// Credentials that coturn accepts until `ttl` from now.
+    fn credentials(&self) -> (String, String) {
+        let expiry = (SystemTime::now() + self.ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.credentials_until(expiry)
+    }
+
+    /// Credentials that coturn accepts until `expiry`, in seconds since the epoch: the
+    /// username is `expiry:username` and the password its HMAC-SHA1 under the shared secret.
+    fn credentials_until(&self, expiry: u64) -> (String, String) {
+        let username = format!("{}:{}", expiry, self.username);
+        let mut mac = Hmac::<Sha1>::new_from_slice(self.secret.as_bytes())
+            .expect("hmac accepts keys of any length");
+        mac.update(username.as_bytes());
+        let password =
+            base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+        (username, password)
+    }
+}
+
+impl IceServerProvider for CoturnIceServers {
+    fn ice_servers(&self) -> BoxFuture<'_, IceServerList> {
+        let (username, password) = self.credentials();
+        Box::pin(future::ready(IceServerList {
+            servers: vec![RTCIceServer {
+                urls: self.urls.clone(),
+                username: Some(username),
+                credential: Some(password),
+                credential_type: Some(RTCIceCredentialType::Password),
+            }],
+            ttl: Some(self.ttl),
+        }))
+    }
+}
+
+impl IceServerProvider for TwilioIceServers {
+    fn ice_servers(&self) -> BoxFuture<'_, IceServerList> {
+        Box::pin(self.get())
+    }
+
+    fn run(self: Arc<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(self.keep_fresh())
+    }
+}
+
+/// The configured providers, asked in order.
+pub struct IceServerChain {
+    configs: Vec<IceProviderConfig>,
+    providers: Vec<Arc<dyn IceServerProvider>>,
+    mode: ChainMode,
+    /// The background work of the providers, stopped when the chain is replaced.
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl IceServerChain {
+    pub fn new(configs: &[IceProviderConfig], mode: ChainMode) -> Self {
+        let providers = configs
+            .iter()
+            .map(|config| -> Arc<dyn IceServerProvider> {
+                match config {
+                    IceProviderConfig::Static { servers } => Arc::new(StaticIceServers {
+                        servers: servers.clone(),
+                    }),
+                    IceProviderConfig::Coturn {
+                        urls,
+                        secret,
+                        ttl_secs,
+                        username,
+                    } => Arc::new(CoturnIceServers {
+                        urls: urls.clone(),
+                        secret: secret.clone(),
+                        ttl: Duration::from_secs(*ttl_secs),
+                        username: username.clone(),
+                    }),
+                    IceProviderConfig::Twilio {
+                        account_sid,
+                        auth_token,
+                    } => Arc::new(TwilioIceServers::new(account_sid, auth_token)),
+                }
+            })
+            .collect();
+        IceServerChain {
+            configs: configs.to_vec(),
+            providers,
+            mode,
+            tasks: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    pub fn is_configured_as(&self, configs: &[IceProviderConfig], mode: ChainMode) -> bool {
+        self.configs == configs && self.mode == mode
+    }
+
+    /// The servers of the providers, valid for as long as those that expire first.
+    pub async fn ice_servers(&self) -> IceServerList {
+        let mut list = IceServerList::default();
+        for provider in &self.providers {
+            let provided = provider.ice_servers().await;
+            if provided.servers.is_empty() {
+                continue;
+            }
+            list.servers.extend(provided.servers);
+            list.ttl = match (list.ttl, provided.ttl) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            if self.mode == ChainMode::FirstAvailable {
+                break;
+            }
+        }
+        list
+    }
+
+    /// Starts the background work of every provider.
+    pub fn spawn(&self) {
+        let mut tasks = self.tasks.lock().unwrap();
+        for provider in &self.providers {
+            tasks.push(tokio::spawn(provider.clone().run()));
+        }
+    }
+}
+
+impl Drop for IceServerChain {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coturn(ttl: Duration) -> CoturnIceServers {
+        CoturnIceServers {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            secret: "north-turn-secret".to_string(),
+            ttl,
+            username: "signaller".to_string(),
+        }
+    }
+
+    #[test]
+    fn signs_coturn_credentials() {
+        let (username, password) = coturn(Duration::from_secs(3600)).credentials_until(1700000000);
+        assert_eq!(username, "1700000000:signaller");
+        // base64(HMAC-SHA1("north-turn-secret", "1700000000:signaller"))
+        assert_eq!(password, "0mUvzyGbjy5T4gKkd982U4ea4gY=");
+    }
+
+    #[tokio::test]
+    async fn coturn_credentials_expire_after_ttl() {
+        let ttl = Duration::from_secs(3600);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let list = coturn(ttl).ice_servers().await;
+        assert_eq!(list.ttl, Some(ttl));
+        let [server] = list.servers.as_slice() else {
+            panic!("expected one server, got {:?}", list.servers);
+        };
+        let username = server.username.as_deref().unwrap();
+        let (expiry, user) = username.split_once(':').unwrap();
+        assert_eq!(user, "signaller");
+        let expiry: u64 = expiry.parse().unwrap();
+        assert!(
+            (now + 3600..=now + 3601).contains(&expiry),
+            "expiry {}",
+            expiry
+        );
+        let (_, password) = coturn(ttl).credentials_until(expiry);
+        assert_eq!(server.credential.as_deref(), Some(password.as_str()));
+    }
+}
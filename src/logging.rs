@@ -0,0 +1,31 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Applies `log::max_level` on top of the `RUST_LOG` filter, so that the level can be changed
+/// while the server runs. Some crates log without checking `log::max_level` first.
+struct Logger(env_logger::Logger);
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// Logs everything `RUST_LOG` allows, up to the level later set with `log::set_max_level`.
+pub fn init() {
+    let logger = env_logger::Builder::from_env(
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "trace"),
+    )
+    .build();
+    log::set_boxed_logger(Box::new(Logger(logger))).expect("logger is only set once");
+    log::set_max_level(LevelFilter::Trace);
+}
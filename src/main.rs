@@ -1,34 +1,56 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::str::FromStr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 use failure::{format_err, Error};
-use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{future, pin_mut, stream::TryStreamExt, StreamExt};
-use log::info;
-use rand::distributions::Distribution;
+use futures_util::future::{self, Either};
+use futures_util::{pin_mut, StreamExt};
+use log::{error, info};
+use rand::distributions::{Alphanumeric, Distribution};
 use rand::{thread_rng, Rng};
+use tokio::signal::unix::{signal, SignalKind};
 use warp::ws::Message;
 use warp::ws::WebSocket;
 use warp::Filter;
 
-use crate::args::Args;
-use crate::signaller_message::SignallerMessage;
-use crate::state::StateType;
+use crate::cluster::BusMessage;
+use crate::connection::Connection;
+use crate::error::{ErrorCode, SignallerError};
+use crate::outbox::{outbox, Outbox};
+use crate::peer::{generate_resume_token, Peer, PeerType};
+use crate::signaller_message::{
+    Envelope, IceServerEntries, RTCSdpType, SignallerMessage, LEGACY_PROTOCOL_VERSION,
+    RTC_ICE_SERVER_PROTOCOL_VERSION,
+};
+use crate::state::{JoinOutcome, StateType};
 
 mod args;
+mod cluster;
 mod config;
+mod connection;
+mod error;
+mod ice_servers;
+mod logging;
 mod metrics;
+mod outbox;
 mod peer;
+mod rate_limit;
+mod redis;
+mod reload;
 mod session;
 mod signaller_message;
+mod snapshot;
 mod state;
 mod twilio_helper;
 
 type Result<T> = std::result::Result<T, Error>;
-type Tx = UnboundedSender<Message>;
 
 const ROOM_ID_LEN: usize = 5;
+const PEER_ID_LEN: usize = 16;
+/// How often shutdown checks whether sessions and connections are gone.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long closed connections get to write what is still queued for them.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
 
 fn generate_room_id(len: usize) -> String {
     pub struct UserFriendlyAlphabet;
@@ -46,202 +68,696 @@ fn generate_room_id(len: usize) -> String {
         .collect()
 }
 
-async fn handle_message(
-    state: &mut state::State,
-    tx: &Tx,
-    raw_payload: &str,
-    socket_addr: SocketAddr,
-) -> Result<()> {
-    let msg: SignallerMessage = serde_json::from_str(raw_payload)?;
-    let forward_message = |state: &state::State, to: String| -> Result<()> {
-        let peer = state
-            .peers
-            .get(&to)
-            .ok_or_else(|| format_err!("Peer does not exist"))?;
-        peer.sender.unbounded_send(Message::text(raw_payload))?;
-        Ok(())
-    };
+fn generate_peer_id(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
 
-    match msg {
-        SignallerMessage::Join { from, room } => {
-            match state.add_viewer(from.clone(), room.clone(), tx.clone()) {
-                Ok(_) => {
-                    info!("{} joined room {}", from, room);
-                    forward_message(state, room)?;
-                }
-                Err(e) => {
-                    info!("Error joining room: {}", e);
-                    tx.unbounded_send(Message::text(serde_json::to_string(
-                        &SignallerMessage::JoinDeclined {
-                            to: from,
-                            reason: e.to_string(),
-                        },
-                    )?))
-                    .unwrap_or_else(|e| {
-                        info!("Error sending failed to join response: {}", e);
-                    });
+/// Handles a single message from a client, returning the reply that should be sent back to it,
+/// if any.
+async fn handle_message(
+    state: &state::State,
+    connection: &mut Connection,
+    mut msg: SignallerMessage,
+) -> Result<Option<SignallerMessage>> {
+    if let SignallerMessage::Hello {
+        protocol_version,
+        client_kind,
+        client_version,
+    } = &msg
+    {
+        let protocol_version = connection.negotiate(*protocol_version, *client_kind)?;
+        info!(
+            "{} connected as {:?} {} using protocol version {}",
+            connection.socket_addr, client_kind, client_version, protocol_version
+        );
+        return Ok(Some(SignallerMessage::Welcome {
+            peer_id: connection.peer_id.clone(),
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version,
+            supported_features: state.supported_features(),
+        }));
+    }
+    connection.ensure_negotiated()?;
+    connection.bind_sender(&mut msg)?;
+    match &msg {
+        SignallerMessage::Join {
+            from,
+            room,
+            password,
+        } => {
+            let peer = connection.to_peer(room.clone(), PeerType::Viewer {});
+            let legacy = peer.is_legacy();
+            let outcome = if state.room_exists(room) {
+                state
+                    .add_viewer(from.clone(), peer, password.as_deref())
+                    .await
+            } else {
+                match state.cluster.remote_owner(room).await {
+                    // Like a viewer waiting for approval, it learns how its join went later.
+                    Ok(Some(node)) => {
+                        info!("Relaying the join of {} to node {}", from, node);
+                        state
+                            .relay_join(node, from.clone(), peer, password.clone())
+                            .map(|()| JoinOutcome::Pending)
+                    }
+                    Ok(None) => {
+                        state
+                            .add_viewer(from.clone(), peer, password.as_deref())
+                            .await
+                    }
+                    Err(e) => Err(e),
                 }
             };
+            return Ok(join_reply(from, room, legacy, outcome));
         }
-        SignallerMessage::Start {} => {
+        SignallerMessage::Start {
+            password,
+            require_approval,
+            max_viewers,
+        } => {
+            if *max_viewers == Some(0) {
+                return Err(SignallerError::new(
+                    ErrorCode::InvalidMessage,
+                    "max_viewers must be at least 1",
+                )
+                .into());
+            }
+            if state.is_shutting_down() {
+                return Err(SignallerError::new(
+                    ErrorCode::ShuttingDown,
+                    "the server is shutting down, reconnect to start a session",
+                )
+                .into());
+            }
+            if state.is_in_session(&connection.peer_id) {
+                return Err(SignallerError::new(
+                    ErrorCode::AlreadyInSession,
+                    "connection is already part of a session",
+                )
+                .into());
+            }
             let tries = 3;
-            let mut room = generate_room_id(ROOM_ID_LEN);
+            let mut room = None;
             for _ in 0..tries {
-                if !state.sessions.contains_key(&room) {
+                let candidate = generate_room_id(ROOM_ID_LEN);
+                // Other nodes may have taken the id already.
+                if !state.room_exists(&candidate) && state.cluster.claim(&candidate).await? {
+                    room = Some(candidate);
                     break;
                 }
-                room = generate_room_id(ROOM_ID_LEN);
             }
+            let room = room.ok_or_else(|| format_err!("no free room id found"))?;
             info!("New room: {}", room);
-            state.add_sharer(room.clone(), tx.clone(), socket_addr)?;
-            tx.unbounded_send(Message::text(serde_json::to_string(
-                &SignallerMessage::StartResponse { room },
-            )?))
-            .unwrap_or_else(|e| {
-                info!("Error sending start response: {}", e);
-            });
+            let peer = connection.to_peer(room.clone(), PeerType::Sharer {});
+            let resume_token = peer.resume_token.clone();
+            let max_viewers = state
+                .add_sharer(
+                    room.clone(),
+                    peer,
+                    password.as_deref(),
+                    *require_approval,
+                    *max_viewers,
+                )
+                .await
+                .inspect_err(|_| {
+                    // Another connection may have started a session with the same id meanwhile.
+                    if !state.room_exists(&room) {
+                        state.cluster.release(room.clone());
+                    }
+                })?;
+            connection.peer_id = room.clone();
+            return Ok(Some(SignallerMessage::StartResponse {
+                room,
+                max_viewers,
+                resume_token,
+            }));
+        }
+        SignallerMessage::Resume { token } => {
+            if state.is_in_session(&connection.peer_id) {
+                return Err(SignallerError::new(
+                    ErrorCode::AlreadyInSession,
+                    "connection is already part of a session",
+                )
+                .into());
+            }
+            // The room and type are taken over from the peer that is resumed.
+            let peer = connection.to_peer(String::new(), PeerType::Viewer {});
+            let (peer_id, room, resume_token) = state.resume(token, peer)?;
+            connection.peer_id = peer_id.clone();
+            return Ok(Some(SignallerMessage::Resumed {
+                room,
+                peer_id,
+                resume_token,
+            }));
+        }
+        SignallerMessage::SetPassword { password } => {
+            state
+                .set_password(&connection.peer_id, password.as_deref())
+                .await?;
+            info!("Password of room {} updated", connection.peer_id);
+        }
+        SignallerMessage::AdmitViewer { viewer } => {
+            state.admit_viewer(&connection.peer_id, viewer)?;
+            info!("{} admitted to room {}", viewer, connection.peer_id);
+        }
+        SignallerMessage::DenyViewer { viewer, reason } => {
+            let reason = reason
+                .clone()
+                .unwrap_or_else(|| ErrorCode::DeniedBySharer.to_string());
+            state.deny_viewer(&connection.peer_id, viewer, reason)?;
+            info!("{} denied from room {}", viewer, connection.peer_id);
+        }
+        SignallerMessage::Kick { viewer, reason } => {
+            state.kick_viewer(&connection.peer_id, viewer, reason.clone(), false)?;
+            info!("{} kicked from room {}", viewer, connection.peer_id);
+        }
+        SignallerMessage::Ban { viewer } => {
+            state.kick_viewer(&connection.peer_id, viewer, None, true)?;
+            info!("{} banned from room {}", viewer, connection.peer_id);
         }
         SignallerMessage::Leave { from } => {
             info!("{} is leaving", from);
-            forward_message(state, state.get_room_id_from_peer_uuid(&from)?)?;
-            state.leave_session(from)?;
+            state.leave_session(from.clone())?;
         }
         SignallerMessage::IceServers {} => {
-            let ice_servers = state.get_ice_servers().await;
-            tx.unbounded_send(Message::text(serde_json::to_string(
-                &SignallerMessage::IceServersResponse { ice_servers },
-            )?))
-            .unwrap_or_else(|e| {
-                info!("Error sending ice server response: {}", e);
-            });
+            let list = state.get_ice_servers().await;
+            let version = connection
+                .protocol_version
+                .unwrap_or(LEGACY_PROTOCOL_VERSION);
+            if version < RTC_ICE_SERVER_PROTOCOL_VERSION {
+                return Ok(Some(SignallerMessage::IceServersResponse {
+                    ice_servers: IceServerEntries::Legacy(
+                        list.servers.iter().flat_map(|s| s.to_legacy()).collect(),
+                    ),
+                    ttl: None,
+                }));
+            }
+            return Ok(Some(SignallerMessage::IceServersResponse {
+                ice_servers: IceServerEntries::Current(list.servers),
+                ttl: list.ttl.map(|ttl| ttl.as_secs()),
+            }));
         }
-        SignallerMessage::Offer { from: _, to }
-        | SignallerMessage::Answer { from: _, to }
-        | SignallerMessage::Ice { from: _, to }
-        | SignallerMessage::RoomClosed { to, room: _ }
-        | SignallerMessage::JoinDeclined { to, reason: _ } => {
-            forward_message(state, to)?;
+        SignallerMessage::Offer { sdp, to, .. } => {
+            sdp.validate(&[RTCSdpType::Offer])?;
+            state.forward(&connection.peer_id, to, &msg)?;
         }
+        SignallerMessage::Answer { sdp, to, .. } => {
+            // A provisional answer may come before the final one.
+            sdp.validate(&[RTCSdpType::Answer, RTCSdpType::Pranswer])?;
+            state.forward(&connection.peer_id, to, &msg)?;
+        }
+        SignallerMessage::Ice { ice, to, .. } => {
+            ice.validate()?;
+            state.count_ice_candidate(&connection.peer_id, to)?;
+            state.forward(&connection.peer_id, to, &msg)?;
+        }
+        SignallerMessage::RoomClosed { to, .. } | SignallerMessage::JoinDeclined { to, .. } => {
+            state.forward_from_sharer(&connection.peer_id, to, &msg)?;
+        }
+        SignallerMessage::Hello { .. } => unreachable!("hello is handled above"),
         SignallerMessage::KeepAlive {}
+        | SignallerMessage::Welcome { .. }
         | SignallerMessage::StartResponse { .. }
-        | SignallerMessage::IceServersResponse { .. } => {}
+        | SignallerMessage::ViewerCount { .. }
+        | SignallerMessage::Resumed { .. }
+        | SignallerMessage::PeerAway { .. }
+        | SignallerMessage::PeerResumed { .. }
+        | SignallerMessage::IceServersResponse { .. }
+        | SignallerMessage::Kicked { .. }
+        | SignallerMessage::JoinAccepted { .. }
+        | SignallerMessage::JoinPending { .. }
+        | SignallerMessage::JoinRequest { .. }
+        | SignallerMessage::JoinRequestCancelled { .. }
+        | SignallerMessage::ServerShuttingDown { .. }
+        | SignallerMessage::Ack {}
+        | SignallerMessage::Error { .. } => {}
     };
-    Ok(())
+    Ok(None)
 }
 
-async fn process_message(
-    msg: Message,
-    state: StateType,
-    tx: &Tx,
-    socket_addr: SocketAddr,
-) -> std::result::Result<(), warp::Error> {
-    if !msg.is_text() {
-        return Ok(());
+/// The reply to a viewer that asked to join a room, if it gets one.
+fn join_reply(
+    from: &String,
+    room: &String,
+    legacy: bool,
+    outcome: Result<JoinOutcome>,
+) -> Option<SignallerMessage> {
+    match outcome {
+        Ok(JoinOutcome::Joined { resume_token }) => {
+            info!("{} joined room {}", from, room);
+            (!legacy).then(|| SignallerMessage::JoinAccepted {
+                room: room.clone(),
+                resume_token,
+            })
+        }
+        Ok(JoinOutcome::Pending) => {
+            info!("{} is waiting to join room {}", from, room);
+            (!legacy).then(|| SignallerMessage::JoinPending { room: room.clone() })
+        }
+        Err(e) => {
+            info!("Error joining room: {}", e);
+            let reason = match e.downcast_ref::<SignallerError>() {
+                Some(e) => e.code.to_string(),
+                None => e.to_string(),
+            };
+            Some(SignallerMessage::JoinDeclined {
+                to: from.clone(),
+                reason,
+            })
+        }
+    }
+}
+
+/// Handles a message from another node about the viewers relayed between them.
+fn handle_bus_message(state: &StateType, message: BusMessage) {
+    match message {
+        BusMessage::Join {
+            node,
+            join_id,
+            from,
+            room,
+            password,
+            protocol_version,
+            hashed_ip,
+        } => {
+            let received = BusMessage::JoinReceived {
+                node: state.cluster.node_id.clone(),
+                join_id,
+                peer: from.clone(),
+            };
+            state
+                .cluster
+                .send(&node, received)
+                .unwrap_or_else(|e| info!("Error acknowledging the join of {}: {}", from, e));
+            let (sender, receiver) = outbox(state.send_queue_depth, state.backpressure_policy);
+            tokio::spawn(state.cluster.clone().relay(
+                receiver,
+                node.clone(),
+                join_id,
+                from.clone(),
+            ));
+            let peer = Peer {
+                room: room.clone(),
+                sender: sender.clone(),
+                socket_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+                peer_type: PeerType::Viewer {},
+                protocol_version,
+                hashed_ip,
+                resume_token: generate_resume_token(),
+                away_until: None,
+                node: Some(node),
+            };
+            let legacy = peer.is_legacy();
+            // Checking the password takes a while, so other messages do not wait for it.
+            let state = state.clone();
+            tokio::spawn(async move {
+                let outcome = state
+                    .add_viewer(from.clone(), peer, password.as_deref())
+                    .await;
+                match join_reply(&from, &room, legacy, outcome) {
+                    // The node of the viewer told it that already.
+                    Some(SignallerMessage::JoinPending { .. }) | None => {}
+                    Some(reply) => send_message(&sender, &reply),
+                }
+                if !state.is_in_session(&from) {
+                    sender.close();
+                }
+            });
+        }
+        BusMessage::Relay {
+            node,
+            from,
+            message,
+        } => {
+            let result = match &message {
+                SignallerMessage::Offer { to, .. } | SignallerMessage::Answer { to, .. } => {
+                    state.forward(&from, to, &message)
+                }
+                SignallerMessage::Ice { to, .. } => state
+                    .count_ice_candidate(&from, to)
+                    .and_then(|()| state.forward(&from, to, &message)),
+                SignallerMessage::Leave { .. } => state.leave_session(from.clone()),
+                _ => Err(format_err!("unexpected relayed message")),
+            };
+            if let Err(e) = result {
+                info!("Error handling message relayed from {}: {}", from, e);
+                let e = match e.downcast::<SignallerError>() {
+                    Ok(e) => e,
+                    Err(_) => SignallerError::new(ErrorCode::Internal, "internal server error"),
+                };
+                let error = SignallerMessage::Error {
+                    code: e.code,
+                    message: e.message,
+                };
+                let deliver = BusMessage::Deliver {
+                    to: from,
+                    payload: serde_json::to_string(&error).unwrap(),
+                };
+                state
+                    .cluster
+                    .send(&node, deliver)
+                    .unwrap_or_else(|e| info!("Error relaying error: {}", e));
+            }
+        }
+        BusMessage::JoinReceived {
+            node,
+            join_id,
+            peer,
+        } => state.join_received(&node, join_id, &peer),
+        BusMessage::Deliver { to, payload } => state.deliver(&to, payload),
+        BusMessage::Left {
+            node,
+            join_id,
+            peer,
+        } => state.forget_relayed(&node, join_id, &peer),
+    }
+}
+
+fn send_message(sender: &Outbox, message: &SignallerMessage) {
+    match serde_json::to_string(message) {
+        Ok(payload) => sender
+            .send(Message::text(payload))
+            .unwrap_or_else(|e| info!("Error sending message: {}", e)),
+        Err(e) => info!("Error serializing message: {}", e),
+    }
+}
+
+/// Handles what other nodes send to this one, for as long as the server runs.
+async fn receive_from_cluster(state: StateType) {
+    let mut incoming = state.cluster.incoming();
+    while let Some(message) = incoming.next().await {
+        handle_bus_message(&state, message);
+    }
+}
+
+async fn process_message(msg: Message, state: &StateType, connection: &mut Connection) {
+    state.touch(&connection.socket_addr);
+    // Whatever arrives after the server closed the connection is ignored while it drains.
+    if !msg.is_text() || connection.sender.is_closed() {
+        return;
     }
 
     if let Ok(s) = msg.to_str() {
-        let mut locked_state = state.lock().await;
-        if let Err(e) = handle_message(&mut locked_state, tx, s, socket_addr).await {
-            info!(
-                "Error occurred when handling message: {}\nMessage: {}",
-                e,
-                msg.to_str().unwrap().to_string()
-            );
+        if let Err(e) = state.check_rate_limit(connection, Envelope::type_of(s).as_deref()) {
+            connection.reply_error(&e, Envelope::request_id_of(s));
+            if connection.rate_limit_violations >= state.settings().max_rate_limit_violations {
+                info!(
+                    "Disconnecting {} for exceeding rate limits",
+                    connection.socket_addr
+                );
+                metrics::NUM_RATE_LIMIT_DISCONNECTS.inc();
+                connection.close();
+            }
+            return;
+        }
+        let envelope = match serde_json::from_str::<Envelope>(s) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                info!("Malformed message: {}\nMessage: {}", e, s);
+                connection.reply_error(
+                    &SignallerError::new(ErrorCode::MalformedMessage, e.to_string()),
+                    Envelope::request_id_of(s),
+                );
+                return;
+            }
+        };
+        if let Err(e) = envelope.check_limits(&state.payload_limits) {
+            info!("Oversized message from {}: {}", connection.socket_addr, e);
+            // An oversized request id is not worth echoing.
+            let request_id = envelope
+                .request_id
+                .filter(|id| id.len() <= state.payload_limits.max_string_length);
+            connection.reply_error(&e, request_id);
+            return;
+        }
+        let request_id = envelope.request_id;
+        match handle_message(state, connection, envelope.message).await {
+            Ok(Some(reply)) => connection.reply(reply, request_id),
+            Ok(None) => {
+                if request_id.is_some() && !connection.is_legacy() {
+                    connection.reply(SignallerMessage::Ack {}, request_id);
+                }
+            }
+            Err(e) => {
+                info!(
+                    "Error occurred when handling message: {}\nMessage: {}",
+                    e, s
+                );
+                let e = match e.downcast::<SignallerError>() {
+                    Ok(e) => e,
+                    Err(_) => SignallerError::new(ErrorCode::Internal, "internal server error"),
+                };
+                connection.reply_error(&e, request_id);
+                if e.code == ErrorCode::UnsupportedProtocolVersion {
+                    info!("Refusing {}: {}", connection.socket_addr, e);
+                    connection.close();
+                }
+            }
         }
     }
-    Ok(())
 }
 
 async fn handle_connection(
-    args: Args,
     state: StateType,
     websocket: WebSocket,
     socket_addr: SocketAddr,
-    real_ip: Option<&IpAddr>,
+    forwarded_for: Option<&IpAddr>,
 ) {
-    let hashed_ip = real_ip
-        .map(|real_ip| metrics::hash_ip(real_ip, &args.ip_hash_salt).unwrap())
-        .unwrap_or("unknown".to_string());
+    // Trusts the proxy in front of the server, if there is one, to set the header.
+    let real_ip = forwarded_for.copied().unwrap_or(socket_addr.ip());
+    let hashed_ip = metrics::hash_ip(&real_ip, &state.ip_hash_salt).unwrap();
+    let hashed_ip_label = hashed_ip.clone();
 
     metrics::NUM_CONNECTED_CLIENTS
-        .with_label_values(&[hashed_ip.as_str()])
+        .with_label_values(&[hashed_ip_label.as_str()])
         .inc();
 
     info!(
-        "WebSocket connection established: {socket_addr}, real IP: {:?}",
+        "WebSocket connection established: {socket_addr}, real IP: {}",
         real_ip
     );
 
     // Insert the write part of this peer to the peer map.
-    let (tx, rx) = unbounded();
     let (outgoing, incoming) = websocket.split();
+    let (tx, rx) = outbox(state.send_queue_depth, state.backpressure_policy);
+    state.on_connect(socket_addr, tx.clone());
+    tokio::spawn(send_pings(tx.clone(), state.ping_interval));
 
-    let handle_incoming =
-        incoming.try_for_each(|msg| process_message(msg, state.clone(), &tx, socket_addr));
+    let sender = tx.clone();
+    let mut connection = Connection::new(generate_peer_id(PEER_ID_LEN), tx, socket_addr, hashed_ip);
+    let mut shutdown = state.shutdown_signal();
+    let handle_incoming = async {
+        let mut incoming = incoming;
+        loop {
+            tokio::select! {
+                msg = incoming.next() => match msg {
+                    Some(Ok(msg)) => process_message(msg, &state, &mut connection).await,
+                    Some(Err(e)) => {
+                        info!("Error receiving from {socket_addr}: {}", e);
+                        if is_too_large(&e) {
+                            connection.reply_error(
+                                &SignallerError::new(
+                                    ErrorCode::PayloadTooLarge,
+                                    format!(
+                                        "messages may not be longer than {} bytes",
+                                        state.max_message_size
+                                    ),
+                                ),
+                                None,
+                            );
+                            connection.close();
+                        }
+                        break;
+                    }
+                    None => break,
+                },
+                Ok(()) = shutdown.changed() => {
+                    if !connection.is_legacy() {
+                        connection.reply(
+                            SignallerMessage::ServerShuttingDown {
+                                reconnect_after: state.reconnect_after.as_secs(),
+                            },
+                            None,
+                        );
+                    }
+                }
+            }
+        }
+    };
 
     let receive_from_others = rx.map(Ok).forward(outgoing);
 
     pin_mut!(handle_incoming, receive_from_others);
-    future::select(handle_incoming, receive_from_others).await;
+    if let Either::Left((_, sending)) = future::select(handle_incoming, receive_from_others).await {
+        // A connection closed for what it sent still gets the error it was closed with.
+        if sender.is_closed() {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, sending).await;
+        }
+    }
 
     metrics::NUM_CONNECTED_CLIENTS
-        .with_label_values(&[hashed_ip.as_str()])
+        .with_label_values(&[hashed_ip_label.as_str()])
         .dec();
 
-    info!("{socket_addr} disconnected, real IP: {:?}", real_ip);
-    state.lock().await.on_disconnect(&socket_addr);
+    info!("{socket_addr} disconnected, real IP: {}", real_ip);
+    state.on_disconnect(&socket_addr);
 }
 
-pub(crate) async fn start_server(addr: SocketAddrV4, args: Args, state: StateType) {
+/// Whether receiving failed because a frame or message was over the size limit.
+fn is_too_large(e: &warp::Error) -> bool {
+    matches!(
+        std::error::Error::source(e).and_then(|e| e.downcast_ref::<tungstenite::Error>()),
+        Some(tungstenite::Error::Capacity(_))
+    )
+}
+
+/// Pings a connection until it closes, so that `State::reap_idle_connections` notices when it
+/// stops answering.
+async fn send_pings(tx: Outbox, ping_interval: Duration) {
+    let mut interval = tokio::time::interval(ping_interval);
+    loop {
+        interval.tick().await;
+        if tx.send(Message::ping(Vec::new())).is_err() {
+            break;
+        }
+    }
+}
+
+/// Waits for SIGTERM or SIGINT, then gives sessions the drain period to end before the
+/// remaining connections are closed.
+async fn shut_down_on_signal(state: StateType) {
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM can be handled");
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+    }
+    info!(
+        "Shutting down, waiting up to {}s for {} sessions to end",
+        state.drain_period.as_secs(),
+        state.active_session_count()
+    );
+    state.begin_shutdown();
+    let deadline = Instant::now() + state.drain_period;
+    while state.active_session_count() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+    }
+    info!(
+        "Closing {} connections, {} sessions did not end",
+        state.connections.len(),
+        state.active_session_count()
+    );
+    state.close_connections();
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    while !state.connections.is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+    }
+    snapshot::save(&state);
+    metrics::flush();
+}
+
+/// Periodic housekeeping that is not driven by any particular connection.
+async fn run_maintenance(state: StateType) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        state.expire_pending_joins();
+        state.expire_relayed_joins();
+        state.expire_away_peers();
+        state.reap_idle_connections();
+        state.prune_rate_limiters();
+    }
+}
+
+/// Serves on every address until the server is shut down. Fails if any address cannot be bound.
+pub(crate) async fn start_server(
+    addrs: Vec<SocketAddr>,
+    metrics_enabled: bool,
+    state: StateType,
+) -> Result<()> {
     metrics::register();
+    tokio::spawn(run_maintenance(state.clone()));
+    if state.snapshot_path.is_some() {
+        tokio::spawn(snapshot::save_periodically(state.clone()));
+    }
+    state.settings().ice_servers.spawn();
+    tokio::spawn(cluster::publish_outgoing(state.clone()));
+    tokio::spawn(receive_from_cluster(state.clone()));
+    tokio::spawn(cluster::renew_claims(state.clone()));
+
+    let max_message_size = state.max_message_size;
+    let shutdown_state = state.clone();
 
     use warp::{addr, any, ws};
-    let metrics_route = warp::path!("metrics").and_then(metrics::metrics_handler);
+    let metrics_route = warp::path!("metrics")
+        .and(any().and_then(move || async move {
+            if metrics_enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        }))
+        .untuple_one()
+        .and_then(metrics::metrics_handler);
     let ws_route = warp::path::end()
         .and(ws())
         .and(addr::remote())
         .and(warp_real_ip::get_forwarded_for())
-        .and(any().map(move || args.clone()))
         .and(any().map(move || state.clone()))
         .map(
-            |ws: ws::Ws,
-             socket_addr: Option<SocketAddr>,
-             real_ip_addrs: Vec<IpAddr>,
-             args: Args,
-             state: StateType| {
-                ws.on_upgrade(move |socket| async move {
-                    handle_connection(
-                        args,
-                        state,
-                        socket,
-                        socket_addr.unwrap(),
-                        real_ip_addrs.last(),
-                    )
-                    .await
-                })
+            move |ws: ws::Ws,
+                  socket_addr: Option<SocketAddr>,
+                  real_ip_addrs: Vec<IpAddr>,
+                  state: StateType| {
+                ws.max_message_size(max_message_size)
+                    .max_frame_size(max_message_size)
+                    .on_upgrade(move |socket| async move {
+                        handle_connection(state, socket, socket_addr.unwrap(), real_ip_addrs.last())
+                            .await
+                    })
             },
         );
 
-    info!("Server listening on {}", addr);
-    warp::serve(metrics_route.or(ws_route)).run(addr).await;
+    let routes = metrics_route.or(ws_route);
+    let mut servers = vec![];
+    for addr in addrs {
+        let (addr, server) = warp::serve(routes.clone())
+            .try_bind_ephemeral(addr)
+            .map_err(|e| format_err!("cannot listen on {}: {}", addr, e))?;
+        info!("Server listening on {}", addr);
+        servers.push(server);
+    }
+    tokio::select! {
+        _ = future::join_all(servers) => {}
+        _ = shut_down_on_signal(shutdown_state) => {}
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init_from_env(
-        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "debug"),
-    );
+    logging::init();
     let args = args::Args::parse();
-    let address = &args.address.split(':').collect::<Vec<&str>>();
-    let address = SocketAddrV4::new(
-        Ipv4Addr::from_str(address[0]).unwrap(),
-        address[1].parse().unwrap(),
-    );
-
-    let config = config::from_env();
+    let config = match config::build(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    log::set_max_level(config.log_level()?);
+    let addresses = config.socket_addrs()?;
     let state = state::State::new(&config);
+    snapshot::restore(&state).await;
+    tokio::spawn(reload::watch(args, config.clone(), state.clone()));
 
-    start_server(address, args, state).await;
+    if let Err(e) = start_server(addresses, config.metrics_enabled, state).await {
+        error!("Failed to start the server: {}", e);
+        std::process::exit(1);
+    }
 
     Ok(())
 }
@@ -1,8 +1,10 @@
 use std::net::IpAddr;
 
 use lazy_static::lazy_static;
-use log::error;
-use prometheus::{Histogram, HistogramOpts, IntGauge, IntGaugeVec, Opts, Registry};
+use log::{error, info};
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
 use warp::{Rejection, Reply};
 
 lazy_static! {
@@ -21,6 +23,85 @@ lazy_static! {
         ])
     )
     .expect("metric can be created");
+    pub static ref NUM_REAPED_CONNECTIONS: IntCounter = IntCounter::new(
+        "num_reaped_connections",
+        "Connections closed for not responding"
+    )
+    .expect("metric can be created");
+    pub static ref NUM_QUEUED_MESSAGES: IntGauge = IntGauge::new(
+        "num_queued_messages",
+        "Messages waiting to be written to connections"
+    )
+    .expect("metric can be created");
+    pub static ref NUM_DROPPED_MESSAGES: IntCounter = IntCounter::new(
+        "num_dropped_messages",
+        "Messages dropped because a connection fell behind"
+    )
+    .expect("metric can be created");
+    pub static ref NUM_SLOW_CONSUMERS_DISCONNECTED: IntCounter = IntCounter::new(
+        "num_slow_consumers_disconnected",
+        "Connections closed because their send queue was full"
+    )
+    .expect("metric can be created");
+    pub static ref NUM_RATE_LIMITED_MESSAGES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "num_rate_limited_messages",
+            "Messages refused by a rate limit"
+        ),
+        &["type", "scope"]
+    )
+    .expect("metric can be created");
+    pub static ref NUM_ICE_SERVER_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "num_ice_server_requests",
+            "ICE server requests by whether the cache could answer them"
+        ),
+        &["result"]
+    )
+    .expect("metric can be created");
+    pub static ref NUM_ICE_SERVER_REFRESH_FAILURES: IntCounter = IntCounter::new(
+        "num_ice_server_refresh_failures",
+        "Failed attempts to fetch ICE server credentials"
+    )
+    .expect("metric can be created");
+    pub static ref NUM_MALFORMED_ICE_SERVERS: IntCounter = IntCounter::new(
+        "num_malformed_ice_servers",
+        "ICE server entries that were skipped because they could not be parsed"
+    )
+    .expect("metric can be created");
+    pub static ref NUM_CONFIG_RELOADS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "num_config_reloads",
+            "Configuration reloads by whether they were applied"
+        ),
+        &["result"]
+    )
+    .expect("metric can be created");
+    pub static ref CONFIG_RELOAD_FAILED: IntGauge = IntGauge::new(
+        "config_reload_failed",
+        "Whether the last configuration reload was refused as invalid"
+    )
+    .expect("metric can be created");
+    pub static ref ICE_CREDENTIALS_AGE_SEC: IntGauge = IntGauge::new(
+        "ice_credentials_age_sec",
+        "Age of the ICE server credentials last handed out"
+    )
+    .expect("metric can be created");
+    pub static ref NUM_RATE_LIMIT_DISCONNECTS: IntCounter = IntCounter::new(
+        "num_rate_limit_disconnects",
+        "Connections closed for repeatedly exceeding rate limits"
+    )
+    .expect("metric can be created");
+    pub static ref NUM_BUS_MESSAGES: IntCounterVec = IntCounterVec::new(
+        Opts::new("num_bus_messages", "Messages exchanged with other nodes"),
+        &["type", "direction"]
+    )
+    .expect("metric can be created");
+    pub static ref NUM_BUS_ERRORS: IntCounter = IntCounter::new(
+        "num_bus_errors",
+        "Failures to reach the room directory or message bus"
+    )
+    .expect("metric can be created");
 }
 
 pub(crate) fn register() {
@@ -33,9 +114,60 @@ pub(crate) fn register() {
     REGISTRY
         .register(Box::new(SESSION_DURATION_SEC.clone()))
         .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_REAPED_CONNECTIONS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_QUEUED_MESSAGES.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_DROPPED_MESSAGES.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_SLOW_CONSUMERS_DISCONNECTED.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_RATE_LIMITED_MESSAGES.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_RATE_LIMIT_DISCONNECTS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_ICE_SERVER_REQUESTS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_ICE_SERVER_REFRESH_FAILURES.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_MALFORMED_ICE_SERVERS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_CONFIG_RELOADS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(CONFIG_RELOAD_FAILED.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(ICE_CREDENTIALS_AGE_SEC.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_BUS_MESSAGES.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_BUS_ERRORS.clone()))
+        .expect("collector can be registered");
 }
 
 pub(crate) async fn metrics_handler() -> Result<impl Reply, Rejection> {
+    Ok(encode())
+}
+
+/// Logs the final value of every metric, since nothing scrapes them once the server exited.
+pub fn flush() {
+    info!("Final metrics:\n{}", encode());
+}
+
+fn encode() -> String {
     use prometheus::Encoder;
     let encoder = prometheus::TextEncoder::new();
 
@@ -60,7 +192,7 @@ pub(crate) async fn metrics_handler() -> Result<impl Reply, Rejection> {
     buffer.clear();
 
     res.push_str(&res_custom);
-    Ok(res)
+    res
 }
 
 pub fn hash_ip(ip: &IpAddr, salt: &str) -> Result<String, argon2::password_hash::Error> {
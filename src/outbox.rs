@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures_util::task::AtomicWaker;
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use warp::ws::Message;
+
+use crate::metrics;
+
+/// What to do when a connection does not read its messages fast enough and its queue fills up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackpressurePolicy {
+    /// Drop the oldest queued ICE candidate to make room, and disconnect only if there is none.
+    DropIce,
+    /// Disconnect the connection straight away.
+    Disconnect,
+}
+
+impl FromStr for BackpressurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_ice" => Ok(BackpressurePolicy::DropIce),
+            "disconnect" => Ok(BackpressurePolicy::Disconnect),
+            _ => Err(format!("unknown backpressure policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    /// The connection is gone.
+    Closed,
+    /// The queue was full and the connection was disconnected because of it.
+    Overflow,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Closed => write!(f, "connection is closed"),
+            SendError::Overflow => write!(f, "send queue is full, disconnecting"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+struct Outgoing {
+    message: Message,
+    /// Whether the message may be dropped when the queue is full.
+    droppable: bool,
+}
+
+struct Queue {
+    messages: VecDeque<Outgoing>,
+    closed: bool,
+}
+
+impl Queue {
+    /// Discards everything still queued and stops accepting messages.
+    fn shut_down(&mut self) {
+        metrics::NUM_QUEUED_MESSAGES.sub(self.messages.len() as i64);
+        self.messages.clear();
+        self.closed = true;
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    waker: AtomicWaker,
+    capacity: usize,
+    policy: BackpressurePolicy,
+}
+
+/// The sending half of the bounded queue of messages for one connection.
+#[derive(Clone)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+/// Yields the queued messages of a connection so they can be written to its websocket.
+pub struct OutboxReceiver {
+    shared: Arc<Shared>,
+}
+
+fn shared(capacity: usize, policy: BackpressurePolicy, closed: bool) -> Arc<Shared> {
+    Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            messages: VecDeque::new(),
+            closed,
+        }),
+        waker: AtomicWaker::new(),
+        capacity,
+        policy,
+    })
+}
+
+pub fn outbox(capacity: usize, policy: BackpressurePolicy) -> (Outbox, OutboxReceiver) {
+    let shared = shared(capacity, policy, false);
+    (
+        Outbox {
+            shared: shared.clone(),
+        },
+        OutboxReceiver { shared },
+    )
+}
+
+/// An outbox that refuses every message as closed, for peers that have no connection yet.
+pub fn disconnected() -> Outbox {
+    Outbox {
+        shared: shared(0, BackpressurePolicy::Disconnect, true),
+    }
+}
+
+impl Outbox {
+    pub fn send(&self, message: Message) -> Result<(), SendError> {
+        self.push(message, false)
+    }
+
+    /// Queues a message the backpressure policy may drop, like an ICE candidate.
+    pub fn send_droppable(&self, message: Message) -> Result<(), SendError> {
+        self.push(message, true)
+    }
+
+    /// Stops accepting messages. Those already queued are still delivered.
+    pub fn close(&self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.waker.wake();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.queue.lock().unwrap().closed
+    }
+
+    fn push(&self, message: Message, droppable: bool) -> Result<(), SendError> {
+        let result = self.try_push(Outgoing { message, droppable });
+        self.shared.waker.wake();
+        result
+    }
+
+    fn try_push(&self, outgoing: Outgoing) -> Result<(), SendError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed {
+            return Err(SendError::Closed);
+        }
+        if queue.messages.len() >= self.shared.capacity {
+            let oldest_droppable = match self.shared.policy {
+                BackpressurePolicy::DropIce => queue.messages.iter().position(|m| m.droppable),
+                BackpressurePolicy::Disconnect => None,
+            };
+            match oldest_droppable {
+                Some(i) => {
+                    queue.messages.remove(i);
+                    metrics::NUM_QUEUED_MESSAGES.dec();
+                    metrics::NUM_DROPPED_MESSAGES.inc();
+                }
+                None => {
+                    queue.shut_down();
+                    metrics::NUM_SLOW_CONSUMERS_DISCONNECTED.inc();
+                    return Err(SendError::Overflow);
+                }
+            }
+        }
+        queue.messages.push_back(outgoing);
+        metrics::NUM_QUEUED_MESSAGES.inc();
+        Ok(())
+    }
+}
+
+impl Stream for OutboxReceiver {
+    type Item = Message;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        // Registering under the lock means a sender cannot queue a message unnoticed.
+        self.shared.waker.register(cx.waker());
+        if let Some(outgoing) = queue.messages.pop_front() {
+            metrics::NUM_QUEUED_MESSAGES.dec();
+            return Poll::Ready(Some(outgoing.message));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for OutboxReceiver {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().shut_down();
+    }
+}
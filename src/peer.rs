@@ -1,16 +1,77 @@
-use futures_channel::mpsc::UnboundedSender;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use log::info;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use warp::ws::Message;
 
-type Tx = UnboundedSender<Message>;
+use crate::outbox::Outbox;
+use crate::signaller_message::{SignallerMessage, LEGACY_PROTOCOL_VERSION};
+
+const RESUME_TOKEN_LEN: usize = 32;
+
+pub fn generate_resume_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(RESUME_TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
 
 pub struct Peer {
     pub room: String,
-    pub sender: Tx,
+    pub sender: Outbox,
+    pub socket_addr: SocketAddr,
     pub peer_type: PeerType,
+    pub protocol_version: u32,
+    pub hashed_ip: Option<String>,
+    /// Lets a new connection take over this peer after the old one dropped.
+    pub resume_token: String,
+    /// Until when the peer may be resumed, if its connection dropped.
+    pub away_until: Option<Instant>,
+    /// The node the peer is connected to, if that is not this one. Its messages are relayed
+    /// through `sender` until it is closed.
+    pub node: Option<String>,
+}
+
+impl Peer {
+    /// Whether the peer negotiated a protocol that knows server initiated messages beyond
+    /// the legacy ones.
+    pub fn is_legacy(&self) -> bool {
+        self.protocol_version <= LEGACY_PROTOCOL_VERSION
+    }
+
+    /// The token the peer can resume with, unless it is connected to another node.
+    pub fn issued_resume_token(&self) -> Option<String> {
+        self.node.is_none().then(|| self.resume_token.clone())
+    }
+
+    pub fn send(&self, msg: &SignallerMessage) {
+        match serde_json::to_string(msg) {
+            Ok(payload) => self
+                .sender
+                .send(Message::text(payload))
+                .unwrap_or_else(|e| {
+                    info!("Error sending message to peer: {}", e);
+                }),
+            Err(e) => info!("Error serializing message to peer: {}", e),
+        }
+    }
+}
+
+/// Once a peer of another node is gone from its session, the end of its outbox tells the
+/// relay to let that node know.
+impl Drop for Peer {
+    fn drop(&mut self) {
+        if self.node.is_some() {
+            self.sender.close();
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum PeerType {
     Sharer {},
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+/// Allows `burst` messages at once, refilled at `per_sec` messages per second.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Rate {
+    pub burst: f64,
+    pub per_sec: f64,
+}
+
+/// The limits for one message type. Connections behind the same IP share the `per_ip` limit.
+/// The IP is the last one in `X-Forwarded-For`, or that of the socket without the header, so
+/// the server has to run behind a proxy that sets the header whenever clients could set it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MessageRateLimit {
+    #[serde(default)]
+    pub per_connection: Option<Rate>,
+    #[serde(default)]
+    pub per_ip: Option<Rate>,
+}
+
+/// Limits keyed by message type. Types without an entry are not limited.
+pub fn default_rate_limits() -> HashMap<String, MessageRateLimit> {
+    let limit = |burst, per_sec, ip_burst, ip_per_sec| MessageRateLimit {
+        per_connection: Some(Rate { burst, per_sec }),
+        per_ip: Some(Rate {
+            burst: ip_burst,
+            per_sec: ip_per_sec,
+        }),
+    };
+    [
+        ("start", limit(3.0, 0.1, 10.0, 0.5)),
+        ("join", limit(5.0, 0.5, 30.0, 2.0)),
+        ("resume", limit(5.0, 0.5, 30.0, 2.0)),
+        ("ice_servers", limit(3.0, 0.1, 10.0, 0.5)),
+        ("set_password", limit(5.0, 0.5, 20.0, 2.0)),
+        ("offer", limit(20.0, 2.0, 200.0, 20.0)),
+        ("answer", limit(20.0, 2.0, 200.0, 20.0)),
+        ("ice", limit(200.0, 50.0, 2000.0, 500.0)),
+    ]
+    .into_iter()
+    .map(|(kind, limit)| (kind.to_string(), limit))
+    .collect()
+}
+
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate.burst,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.updated_at.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_sec).min(self.rate.burst);
+        self.updated_at = Instant::now();
+    }
+
+    fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Token buckets per message type for one connection or IP.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Takes a token for a message of the given type, returning whether one was left.
+    pub fn try_take(&mut self, kind: &str, rate: &Rate) -> bool {
+        match self.buckets.get_mut(kind) {
+            Some(bucket) if bucket.rate == *rate => bucket.try_take(),
+            _ => {
+                let mut bucket = TokenBucket::new(*rate);
+                let taken = bucket.try_take();
+                self.buckets.insert(kind.to_string(), bucket);
+                taken
+            }
+        }
+    }
+
+    /// Whether every bucket refilled, so that forgetting them changes nothing.
+    pub fn is_idle(&mut self) -> bool {
+        self.buckets.values_mut().all(|bucket| {
+            bucket.refill();
+            bucket.tokens >= bucket.rate.burst
+        })
+    }
+}
@@ -0,0 +1,93 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::args::Args;
+use crate::config::{self, Config};
+use crate::metrics;
+use crate::state::StateType;
+
+/// How often the configuration file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings that take effect without a restart.
+const RELOADABLE_SETTINGS: &[&str] = &[
+    "log_level",
+    "twilio_account_sid",
+    "twilio_auth_token",
+    "ice_providers",
+    "ice_provider_mode",
+    "rate_limits",
+    "max_rate_limit_violations",
+];
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reloads the configuration whenever its file changes or the process receives SIGHUP.
+/// Connections and sessions are not affected.
+pub async fn watch(args: Args, mut config: Config, state: StateType) {
+    let mut hangup = signal(SignalKind::hangup()).expect("SIGHUP can be handled");
+    let mut modified = args.config.as_deref().and_then(modified_at);
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let now = args.config.as_deref().and_then(modified_at);
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+                info!("Configuration file changed, reloading it");
+            }
+            _ = hangup.recv() => info!("Received SIGHUP, reloading the configuration"),
+        }
+        config = reload(&args, config, &state);
+    }
+}
+
+/// Applies the reloadable settings that changed, returning the configuration now in effect.
+fn reload(args: &Args, current: Config, state: &StateType) -> Config {
+    let config = match config::build(args) {
+        Ok(config) => config,
+        Err(e) => {
+            error!(
+                "Keeping the current configuration, the new one is invalid: {}",
+                e
+            );
+            metrics::NUM_CONFIG_RELOADS
+                .with_label_values(&["invalid"])
+                .inc();
+            metrics::CONFIG_RELOAD_FAILED.set(1);
+            return current;
+        }
+    };
+    metrics::CONFIG_RELOAD_FAILED.set(0);
+    let diff = current.diff(&config);
+    if diff.is_empty() {
+        info!("Configuration did not change");
+        metrics::NUM_CONFIG_RELOADS
+            .with_label_values(&["unchanged"])
+            .inc();
+        return current;
+    }
+    for (setting, change) in &diff {
+        if RELOADABLE_SETTINGS.contains(&setting.as_str()) {
+            info!("Applying {}: {}", setting, change);
+        } else {
+            warn!("{} changed, restart the server to apply it", setting);
+        }
+    }
+    // Validation made sure that the level can be parsed.
+    if let Ok(level) = config.log_level() {
+        log::set_max_level(level);
+    }
+    state.reload(&config);
+    metrics::NUM_CONFIG_RELOADS
+        .with_label_values(&["applied"])
+        .inc();
+    config
+}
@@ -1,21 +1,271 @@
-use std::collections::HashSet;
-use std::net::SocketAddr;
-use std::time::SystemTime;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::{thread_rng, RngCore};
+
+use crate::error::{ErrorCode, SignallerError};
+use crate::peer::Peer;
+use crate::signaller_message::SignallerMessage;
+use crate::snapshot::{PeerSnapshot, SessionSnapshot};
+
+/// Number of wrong passwords a room accepts within `FAILED_JOIN_WINDOW`.
+const MAX_FAILED_JOINS: usize = 5;
+const FAILED_JOIN_WINDOW: Duration = Duration::from_secs(60);
+
+/// A viewer waiting for the sharer to admit it.
+pub struct PendingJoin {
+    pub peer: Peer,
+    pub requested_at: Instant,
+}
 
 pub struct Session {
     pub sharer: String,
     pub viewers: HashSet<String>,
+    /// The sharer and the admitted viewers, by peer id.
+    pub peers: HashMap<String, Peer>,
+    /// Set once the session ended, for whoever was still waiting for its lock.
+    pub closed: bool,
     pub start_time: SystemTime,
-    pub sharer_socket_addr: SocketAddr,
+    pub max_viewers: usize,
+    /// Whether viewers wait in `pending` until the sharer admits them.
+    pub require_approval: bool,
+    pub pending: HashMap<String, PendingJoin>,
+    /// Peer ids and hashed IPs that may not join for the rest of the session.
+    pub banned: HashSet<String>,
+    /// Number of ICE candidates exchanged by each pair of peers while both are in the session.
+    ice_candidates: HashMap<(String, String), usize>,
+    password_hash: Option<String>,
+    failed_joins: VecDeque<Instant>,
 }
 
 impl Session {
-    pub fn new(sharer: String, sharer_socket_addr: SocketAddr) -> Self {
+    pub fn new(sharer: String, max_viewers: usize) -> Self {
         Session {
             sharer,
             viewers: Default::default(),
+            peers: Default::default(),
+            closed: false,
             start_time: SystemTime::now(),
-            sharer_socket_addr,
+            max_viewers,
+            require_approval: false,
+            pending: Default::default(),
+            banned: Default::default(),
+            ice_candidates: Default::default(),
+            password_hash: None,
+            failed_joins: Default::default(),
+        }
+    }
+
+    /// The session without its peers, which the caller restores on its own.
+    pub fn from_snapshot(snapshot: &SessionSnapshot) -> Self {
+        Session {
+            start_time: snapshot.start_time,
+            require_approval: snapshot.require_approval,
+            banned: snapshot.banned.iter().cloned().collect(),
+            password_hash: snapshot.password_hash.clone(),
+            ..Session::new(snapshot.room.clone(), snapshot.max_viewers)
+        }
+    }
+
+    /// The session with the peers that can resume it. Legacy peers never learn their resume
+    /// token, and viewers connected to other nodes cannot resume.
+    pub fn to_snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            room: self.sharer.clone(),
+            start_time: self.start_time,
+            max_viewers: self.max_viewers,
+            require_approval: self.require_approval,
+            password_hash: self.password_hash.clone(),
+            banned: self.banned.iter().cloned().collect(),
+            peers: self
+                .peers
+                .iter()
+                .filter(|(_, peer)| !peer.is_legacy() && peer.node.is_none())
+                .map(|(id, peer)| PeerSnapshot {
+                    id: id.clone(),
+                    peer_type: peer.peer_type,
+                    protocol_version: peer.protocol_version,
+                    hashed_ip: peer.hashed_ip.clone(),
+                    resume_token: peer.resume_token.clone(),
+                })
+                .collect(),
         }
     }
+
+    /// Counts an ICE candidate sent from one peer to another, failing once the two exchanged
+    /// `max` of them.
+    pub fn count_ice_candidate(
+        &mut self,
+        from: &str,
+        to: &str,
+        max: usize,
+    ) -> Result<(), SignallerError> {
+        let pair = if from < to { (from, to) } else { (to, from) };
+        let count = self
+            .ice_candidates
+            .entry((pair.0.to_string(), pair.1.to_string()))
+            .or_default();
+        if *count >= max {
+            return Err(SignallerError::new(
+                ErrorCode::TooManyCandidates,
+                "too many ice candidates exchanged with this peer",
+            ));
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    /// Forgets the ICE candidates a peer exchanged, once it left the session.
+    pub fn forget_ice_candidates(&mut self, id: &str) {
+        self.ice_candidates
+            .retain(|(a, b), _| a.as_str() != id && b.as_str() != id);
+    }
+
+    pub fn sharer_peer(&self) -> &Peer {
+        &self.peers[&self.sharer]
+    }
+
+    /// Lets the sharer know how many viewers its room has after it changed.
+    pub fn notify_viewer_count(&self) {
+        let sharer = self.sharer_peer();
+        if !sharer.is_legacy() {
+            sharer.send(&SignallerMessage::ViewerCount {
+                current: self.viewers.len(),
+                max: self.max_viewers,
+            });
+        }
+    }
+
+    /// Sends a message about a peer to every other peer in the room that negotiated a protocol
+    /// newer than the legacy one, skipping peers that are away.
+    pub fn broadcast(&self, about: &str, msg: &SignallerMessage) {
+        for (id, peer) in &self.peers {
+            if id != about && !peer.is_legacy() && peer.away_until.is_none() {
+                peer.send(msg);
+            }
+        }
+    }
+
+    /// Viewers waiting for approval count towards the limit, so that admitting them never
+    /// overfills the room.
+    pub fn check_capacity(&self) -> Result<(), SignallerError> {
+        if self.viewers.len() + self.pending.len() >= self.max_viewers {
+            return Err(SignallerError::new(ErrorCode::RoomFull, "room is full"));
+        }
+        Ok(())
+    }
+
+    pub fn check_banned(&self, id: &String, peer: &Peer) -> Result<(), SignallerError> {
+        let ip_banned = peer
+            .hashed_ip
+            .as_ref()
+            .is_some_and(|ip| self.banned.contains(ip));
+        if ip_banned || self.banned.contains(id) {
+            return Err(SignallerError::new(
+                ErrorCode::Banned,
+                "you were removed from this room",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sets or clears the hash of the password viewers need to join.
+    pub fn set_password_hash(&mut self, password_hash: Option<String>) {
+        self.password_hash = password_hash;
+        self.failed_joins.clear();
+    }
+
+    /// Checks the password supplied by a joining viewer, throttling repeated failures.
+    ///
+    /// Verifying takes long, so it happens outside of the session: unless `verified` tells
+    /// how the password compares to the current hash, that hash is returned to check it
+    /// against, and the viewer is checked again with the result.
+    pub fn check_password(
+        &mut self,
+        password: Option<&str>,
+        verified: Option<&VerifiedPassword>,
+    ) -> Result<Option<String>, SignallerError> {
+        let Some(hash) = &self.password_hash else {
+            return Ok(None);
+        };
+        while self
+            .failed_joins
+            .front()
+            .is_some_and(|t| t.elapsed() > FAILED_JOIN_WINDOW)
+        {
+            self.failed_joins.pop_front();
+        }
+        if self.failed_joins.len() >= MAX_FAILED_JOINS {
+            return Err(SignallerError::new(
+                ErrorCode::TooManyAttempts,
+                "too many wrong passwords, try again later",
+            ));
+        }
+        if password.is_none() {
+            return Err(SignallerError::new(
+                ErrorCode::PasswordRequired,
+                "room requires a password",
+            ));
+        }
+        match verified {
+            // The password may have changed while it was verified.
+            Some(verified) if verified.hash == *hash => {
+                if !verified.matches {
+                    self.failed_joins.push_back(Instant::now());
+                    return Err(SignallerError::new(
+                        ErrorCode::WrongPassword,
+                        "wrong password",
+                    ));
+                }
+                Ok(None)
+            }
+            _ => Ok(Some(hash.clone())),
+        }
+    }
+}
+
+/// How a password compares to a password hash.
+pub struct VerifiedPassword {
+    pub hash: String,
+    pub matches: bool,
+}
+
+/// Hashes a password to store in a session, on a thread that may block. Empty passwords
+/// mean there is none.
+pub async fn hash_password(password: Option<&str>) -> Result<Option<String>, SignallerError> {
+    let Some(password) = password.filter(|p| !p.is_empty()).map(str::to_owned) else {
+        return Ok(None);
+    };
+    let hash = tokio::task::spawn_blocking(move || {
+        let mut salt = [0u8; 16];
+        thread_rng().fill_bytes(&mut salt);
+        SaltString::encode_b64(&salt).and_then(|salt| {
+            Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+        })
+    })
+    .await
+    .map_err(|e| SignallerError::new(ErrorCode::Internal, e.to_string()))?
+    .map_err(|e| SignallerError::new(ErrorCode::Internal, e.to_string()))?;
+    Ok(Some(hash))
+}
+
+/// Verifies a password against a password hash, on a thread that may block.
+pub async fn verify_password(
+    hash: String,
+    password: String,
+) -> Result<VerifiedPassword, SignallerError> {
+    tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&hash)
+            .map_err(|e| SignallerError::new(ErrorCode::Internal, e.to_string()))?;
+        let matches = Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok();
+        Ok(VerifiedPassword { hash, matches })
+    })
+    .await
+    .map_err(|e| SignallerError::new(ErrorCode::Internal, e.to_string()))?
 }
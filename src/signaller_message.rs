@@ -1,7 +1,70 @@
 use serde::{Deserialize, Serialize};
 
+use crate::error::{ErrorCode, SignallerError};
+
+/// The protocol version spoken by this server.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// The oldest protocol version this server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// The version assumed for clients that never send a `Hello`.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+/// The first protocol version that gets ICE servers shaped like `RTCIceServer`.
+pub const RTC_ICE_SERVER_PROTOCOL_VERSION: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientKind {
+    Sharer,
+    Viewer,
+}
+
+/// Optional capabilities advertised to clients in `Welcome`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    IceServers,
+    TypedErrors,
+    Passwords,
+    JoinApproval,
+    Kick,
+    MaxViewers,
+    Resume,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RTCIceCredentialType {
+    Password,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RTCIceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential_type: Option<RTCIceCredentialType>,
+}
+
+impl RTCIceServer {
+    /// The server as clients that predate `RTCIceServer` expect it, one entry per URL.
+    pub fn to_legacy(&self) -> Vec<LegacyIceServer> {
+        self.urls
+            .iter()
+            .map(|url| LegacyIceServer {
+                url: url.clone(),
+                username: self.username.clone().unwrap_or_default(),
+                password: self.credential.clone().unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
-pub struct IceServer {
+pub struct LegacyIceServer {
     pub url: String,
     #[serde(default)]
     pub username: String,
@@ -9,46 +72,393 @@ pub struct IceServer {
     pub password: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The ICE servers of an `IceServersResponse`, in the shape the client's protocol version
+/// expects.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IceServerEntries {
+    Current(Vec<RTCIceServer>),
+    Legacy(Vec<LegacyIceServer>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RTCSdpType {
+    Offer,
+    Pranswer,
+    Answer,
+    Rollback,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RTCSessionDescription {
+    #[serde(rename = "type")]
+    pub sdp_type: RTCSdpType,
+    #[serde(default)]
+    pub sdp: String,
+}
+
+impl RTCSessionDescription {
+    /// Checks that this is a well-formed description of one of the expected types.
+    pub fn validate(&self, expected: &[RTCSdpType]) -> Result<(), SignallerError> {
+        if !expected.contains(&self.sdp_type) {
+            return Err(SignallerError::new(
+                ErrorCode::InvalidSdp,
+                format!(
+                    "expected sdp of type {:?}, got {:?}",
+                    expected, self.sdp_type
+                ),
+            ));
+        }
+        if !self.sdp.starts_with("v=0") {
+            return Err(SignallerError::new(
+                ErrorCode::InvalidSdp,
+                "sdp must start with a version line",
+            ));
+        }
+        if !self.sdp.lines().any(|line| line.starts_with("m=")) {
+            return Err(SignallerError::new(
+                ErrorCode::InvalidSdp,
+                "sdp does not contain any media description",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RTCIceCandidateInit {
+    pub candidate: String,
+    #[serde(default)]
+    pub sdp_mid: Option<String>,
+    #[serde(default, rename = "sdpMLineIndex")]
+    pub sdp_m_line_index: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username_fragment: Option<String>,
+}
+
+impl RTCIceCandidateInit {
+    /// Checks that this candidate can be added by the receiving peer.
+    /// An empty candidate signals the end of candidates.
+    pub fn validate(&self) -> Result<(), SignallerError> {
+        if self.candidate.is_empty() {
+            return Ok(());
+        }
+        if !self.candidate.starts_with("candidate:") {
+            return Err(SignallerError::new(
+                ErrorCode::InvalidIceCandidate,
+                "candidate must start with \"candidate:\"",
+            ));
+        }
+        if self.sdp_mid.is_none() && self.sdp_m_line_index.is_none() {
+            return Err(SignallerError::new(
+                ErrorCode::InvalidIceCandidate,
+                "either sdpMid or sdpMLineIndex must be set",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum SignallerMessage {
+    Hello {
+        protocol_version: u32,
+        client_kind: ClientKind,
+        client_version: String,
+    },
+    Welcome {
+        /// The id assigned to this connection. Sharers are addressed by their room id instead
+        /// once they start a session.
+        peer_id: String,
+        server_version: String,
+        protocol_version: u32,
+        supported_features: Vec<Feature>,
+    },
     Offer {
-        // sdp: RTCSessionDescription,
+        sdp: RTCSessionDescription,
+        #[serde(default)]
         from: String,
         to: String,
     },
     Answer {
-        // sdp: RTCSessionDescription,
+        sdp: RTCSessionDescription,
+        #[serde(default)]
         from: String,
         to: String,
     },
     Ice {
-        // ice: RTCIceCandidateInit,
+        ice: RTCIceCandidateInit,
+        #[serde(default)]
         from: String,
         to: String,
     },
     Join {
+        #[serde(default)]
         from: String,
         room: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
     },
     JoinDeclined {
         to: String,
         reason: String,
     },
-    Start {},
+    /// Sent to a viewer once it is part of the room. Viewers of rooms on other nodes get no
+    /// resume token, since they cannot resume.
+    JoinAccepted {
+        room: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+    },
+    /// Sent to a viewer that waits for the sharer to admit it.
+    JoinPending {
+        room: String,
+    },
+    /// Asks the sharer to admit or deny a viewer.
+    JoinRequest {
+        from: String,
+        room: String,
+    },
+    /// Tells the sharer that a viewer stopped waiting to be admitted.
+    JoinRequestCancelled {
+        from: String,
+    },
+    AdmitViewer {
+        viewer: String,
+    },
+    DenyViewer {
+        viewer: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Removes a viewer from the sharer's room. It cannot rejoin from the same connection.
+    Kick {
+        viewer: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Like `Kick`, but also keeps the viewer's IP out of the room.
+    Ban {
+        viewer: String,
+    },
+    /// Tells a viewer that the sharer removed it from the room.
+    Kicked {
+        room: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    Start {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+        /// Whether viewers need to be admitted by the sharer.
+        #[serde(default)]
+        require_approval: bool,
+        /// Limits the number of viewers below the server wide maximum.
+        #[serde(default)]
+        max_viewers: Option<usize>,
+    },
     StartResponse {
         room: String,
+        max_viewers: usize,
+        resume_token: String,
+    },
+    /// Takes over the peer that was given the token, after the connection it used dropped.
+    Resume {
+        token: String,
+    },
+    Resumed {
+        room: String,
+        peer_id: String,
+        /// Tokens are single use, this one replaces the token that was just used.
+        resume_token: String,
+    },
+    /// Tells the other peers in a room that a peer lost its connection and may resume.
+    PeerAway {
+        peer: String,
+    },
+    PeerResumed {
+        peer: String,
+    },
+    /// Tells the sharer how many viewers are in its room.
+    ViewerCount {
+        current: usize,
+        max: usize,
+    },
+    /// Sets or clears the password of the sharer's room.
+    SetPassword {
+        password: Option<String>,
     },
     Leave {
+        #[serde(default)]
         from: String,
     },
     RoomClosed {
         to: String,
         room: String,
     },
+    /// Tells clients that the server is going away and that they should reconnect after
+    /// `reconnect_after` seconds.
+    ServerShuttingDown {
+        reconnect_after: u64,
+    },
     KeepAlive {},
     IceServers {},
     IceServersResponse {
-        ice_servers: Vec<IceServer>,
+        ice_servers: IceServerEntries,
+        /// Seconds after which the credentials expire and clients should ask again.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+    },
+    Ack {},
+    Error {
+        code: ErrorCode,
+        message: String,
     },
 }
+
+/// Size limits a message has to respect before it is handled or forwarded.
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadLimits {
+    pub max_sdp_length: usize,
+    /// Applies to ids, reasons, passwords and every other string a client sends.
+    pub max_string_length: usize,
+}
+
+impl PayloadLimits {
+    fn check_string(&self, name: &str, value: &str) -> Result<(), SignallerError> {
+        if value.len() > self.max_string_length {
+            return Err(SignallerError::new(
+                ErrorCode::PayloadTooLarge,
+                format!("{} is longer than {} bytes", name, self.max_string_length),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl SignallerMessage {
+    /// The sender of a client message, which the server fills in from the connection.
+    pub fn sender_mut(&mut self) -> Option<&mut String> {
+        match self {
+            SignallerMessage::Offer { from, .. }
+            | SignallerMessage::Answer { from, .. }
+            | SignallerMessage::Ice { from, .. }
+            | SignallerMessage::Join { from, .. }
+            | SignallerMessage::Leave { from } => Some(from),
+            _ => None,
+        }
+    }
+
+    /// The strings a client can choose freely, by field name. Messages only the server sends
+    /// have none.
+    fn string_fields(&self) -> Vec<(&'static str, &str)> {
+        match self {
+            SignallerMessage::Hello { client_version, .. } => {
+                vec![("client_version", client_version)]
+            }
+            SignallerMessage::Offer { from, to, .. }
+            | SignallerMessage::Answer { from, to, .. } => {
+                vec![("from", from), ("to", to)]
+            }
+            SignallerMessage::Ice { ice, from, to } => {
+                let mut fields = vec![
+                    ("candidate", ice.candidate.as_str()),
+                    ("from", from),
+                    ("to", to),
+                ];
+                fields.extend(ice.sdp_mid.as_deref().map(|mid| ("sdpMid", mid)));
+                fields.extend(
+                    ice.username_fragment
+                        .as_deref()
+                        .map(|ufrag| ("usernameFragment", ufrag)),
+                );
+                fields
+            }
+            SignallerMessage::Join {
+                from,
+                room,
+                password,
+            } => {
+                let mut fields = vec![("from", from.as_str()), ("room", room)];
+                fields.extend(password.as_deref().map(|password| ("password", password)));
+                fields
+            }
+            SignallerMessage::JoinDeclined { to, reason } => vec![("to", to), ("reason", reason)],
+            SignallerMessage::RoomClosed { to, room } => vec![("to", to), ("room", room)],
+            SignallerMessage::AdmitViewer { viewer } | SignallerMessage::Ban { viewer } => {
+                vec![("viewer", viewer)]
+            }
+            SignallerMessage::DenyViewer { viewer, reason }
+            | SignallerMessage::Kick { viewer, reason } => {
+                let mut fields = vec![("viewer", viewer.as_str())];
+                fields.extend(reason.as_deref().map(|reason| ("reason", reason)));
+                fields
+            }
+            SignallerMessage::Start { password, .. }
+            | SignallerMessage::SetPassword { password } => password
+                .as_deref()
+                .map(|password| ("password", password))
+                .into_iter()
+                .collect(),
+            SignallerMessage::Resume { token } => vec![("token", token)],
+            SignallerMessage::Leave { from } => vec![("from", from)],
+            _ => vec![],
+        }
+    }
+
+    pub fn check_limits(&self, limits: &PayloadLimits) -> Result<(), SignallerError> {
+        if let SignallerMessage::Offer { sdp, .. } | SignallerMessage::Answer { sdp, .. } = self {
+            if sdp.sdp.len() > limits.max_sdp_length {
+                return Err(SignallerError::new(
+                    ErrorCode::PayloadTooLarge,
+                    format!("sdp is longer than {} bytes", limits.max_sdp_length),
+                ));
+            }
+        }
+        for (name, value) in self.string_fields() {
+            limits.check_string(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A message together with the optional id a client attached to it. Replies to the message
+/// carry the same id so that clients can match them to their requests.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(flatten)]
+    pub message: SignallerMessage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl Envelope {
+    pub fn check_limits(&self, limits: &PayloadLimits) -> Result<(), SignallerError> {
+        if let Some(request_id) = &self.request_id {
+            limits.check_string("request_id", request_id)?;
+        }
+        self.message.check_limits(limits)
+    }
+
+    /// Best effort extraction of the request id from a payload that failed to parse.
+    pub fn request_id_of(raw_payload: &str) -> Option<String> {
+        serde_json::from_str::<serde_json::Value>(raw_payload)
+            .ok()?
+            .get("request_id")?
+            .as_str()
+            .map(str::to_owned)
+    }
+
+    /// Best effort extraction of the message type, which is known before the payload is
+    /// validated.
+    pub fn type_of(raw_payload: &str) -> Option<String> {
+        serde_json::from_str::<serde_json::Value>(raw_payload)
+            .ok()?
+            .get("type")?
+            .as_str()
+            .map(str::to_owned)
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+use failure::{format_err, Error};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::peer::PeerType;
+use crate::state::StateType;
+
+/// The sessions of a server, written to disk so that the next server can bring them back.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub taken_at: SystemTime,
+    pub sessions: Vec<SessionSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub room: String,
+    pub start_time: SystemTime,
+    pub max_viewers: usize,
+    pub require_approval: bool,
+    pub password_hash: Option<String>,
+    pub banned: Vec<String>,
+    /// The peers that can resume, which excludes legacy viewers.
+    pub peers: Vec<PeerSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerSnapshot {
+    pub id: String,
+    pub peer_type: PeerType,
+    pub protocol_version: u32,
+    pub hashed_ip: Option<String>,
+    pub resume_token: String,
+}
+
+/// Replaces the snapshot at `path`. The file is swapped in whole, so that a crash while
+/// writing never leaves a partial snapshot behind. Only the server's user can read it, since
+/// its resume tokens let anyone take over the sessions.
+pub fn write(path: &Path, snapshot: &Snapshot) -> Result<(), Error> {
+    let contents = serde_json::to_vec(snapshot)?;
+    let tmp = path.with_extension("tmp");
+    // The mode only applies to new files, so a leftover one is replaced.
+    let _ = fs::remove_file(&tmp);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(&contents))
+        .map_err(|e| format_err!("cannot write {}: {}", tmp.display(), e))?;
+    fs::rename(&tmp, path).map_err(|e| format_err!("cannot replace {}: {}", path.display(), e))
+}
+
+/// Reads the snapshot at `path`, if there is one.
+pub fn read(path: &Path) -> Result<Option<Snapshot>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents =
+        fs::read(path).map_err(|e| format_err!("cannot read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&contents)
+        .map(Some)
+        .map_err(|e| format_err!("invalid snapshot {}: {}", path.display(), e))
+}
+
+/// Writes a snapshot of the current sessions, logging failures.
+pub fn save(state: &StateType) {
+    let Some(path) = &state.snapshot_path else {
+        return;
+    };
+    let snapshot = state.snapshot();
+    match write(path, &snapshot) {
+        Ok(()) => debug!(
+            "Saved {} sessions to {}",
+            snapshot.sessions.len(),
+            path.display()
+        ),
+        Err(e) => error!("Failed to save sessions: {}", e),
+    }
+}
+
+/// Brings back the sessions of the previous server, if it left a recent enough snapshot.
+pub async fn restore(state: &StateType) {
+    let Some(path) = &state.snapshot_path else {
+        return;
+    };
+    match read(path) {
+        Ok(Some(snapshot)) => state.restore(snapshot).await,
+        Ok(None) => info!("No sessions to restore from {}", path.display()),
+        Err(e) => error!("Failed to restore sessions: {}", e),
+    }
+}
+
+/// Saves the sessions every snapshot interval, so that a crash loses little.
+pub async fn save_periodically(state: StateType) {
+    let mut interval = tokio::time::interval(state.snapshot_interval);
+    loop {
+        interval.tick().await;
+        save(&state);
+    }
+}
@@ -1,162 +1,1097 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use base64::Engine;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
 use failure::{format_err, Error};
-use futures_channel::mpsc::UnboundedSender;
 use log::info;
-use tokio::sync::Mutex;
-use twilio::TwilioAuthentication;
+use tokio::sync::watch;
 use warp::ws::Message;
 
+use crate::cluster::{BusMessage, Cluster};
 use crate::config::Config;
+use crate::connection::{Connection, ConnectionHandle};
+use crate::error::{ErrorCode, SignallerError};
+use crate::ice_servers::{IceServerChain, IceServerList};
 use crate::metrics;
+use crate::outbox::{self, BackpressurePolicy, Outbox};
 use crate::peer::{Peer, PeerType};
-use crate::session::Session;
-use crate::signaller_message::{IceServer, SignallerMessage};
-use crate::twilio_helper::get_twilio_ice_servers;
+use crate::rate_limit::{MessageRateLimit, RateLimiter};
+use crate::session::{self, PendingJoin, Session, VerifiedPassword};
+use crate::signaller_message::{Feature, PayloadLimits, SignallerMessage};
+use crate::snapshot::Snapshot;
 
 type Result<T> = std::result::Result<T, Error>;
-type Tx = UnboundedSender<Message>;
 
+/// How long a viewer waits for the sharer to admit it before it is declined.
+pub const PENDING_JOIN_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a viewer waits for the node owning a room to get its join before it is declined.
+const RELAYED_JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum JoinOutcome {
+    Joined {
+        resume_token: Option<String>,
+    },
+    /// The viewer waits for the sharer to admit it.
+    Pending,
+}
+
+/// How far adding a viewer got while its session was locked.
+enum Admission {
+    Done(JoinOutcome),
+    /// The password of the viewer needs to be verified against `hash` first.
+    Verify {
+        hash: String,
+        peer: Peer,
+    },
+}
+
+/// A viewer connected to this node that is part of a room another node owns.
+struct RelayedPeer {
+    /// The node owning the room.
+    node: String,
+    join_id: u64,
+    sender: Outbox,
+    socket_addr: SocketAddr,
+    /// When the join was relayed, until the node owning the room lets us know it got it.
+    sent_at: Option<Instant>,
+}
+
+/// Shared by all connections. Each room is locked on its own, so that signalling in one room
+/// never waits for another, and the indexes across rooms are sharded maps.
 pub struct State {
-    pub sessions: HashMap<String, Session>,
-    pub sharer_socket_addr_to_room: HashMap<SocketAddr, String>,
-    pub peers: HashMap<String, Peer>,
-    pub twilio_client: Option<twilio::TwilioClient>,
-    pub twilio_account_sid: Option<String>,
+    pub connections: DashMap<SocketAddr, ConnectionHandle>,
+    rooms: DashMap<String, Arc<Mutex<Session>>>,
+    /// Maps the socket of every sharer, viewer and waiting viewer to its peer id.
+    socket_addr_to_peer: DashMap<SocketAddr, String>,
+    /// Maps every sharer, viewer and waiting viewer to its room.
+    peer_rooms: DashMap<String, String>,
+    /// Maps resume tokens to the peer they belong to.
+    resume_tokens: DashMap<String, String>,
+    /// Maps the viewers of this node that joined rooms of other nodes to how to reach them.
+    relayed_peers: DashMap<String, RelayedPeer>,
+    pub cluster: Arc<Cluster>,
+    pub max_viewers_per_room: usize,
+    pub resume_grace_period: Duration,
+    pub ping_interval: Duration,
+    pub idle_timeout: Duration,
+    pub send_queue_depth: usize,
+    pub backpressure_policy: BackpressurePolicy,
+    settings: RwLock<Arc<Settings>>,
+    /// Rate limits shared by all connections from the same hashed IP.
+    pub ip_rate_limiters: DashMap<String, RateLimiter>,
+    pub max_message_size: usize,
+    pub payload_limits: PayloadLimits,
+    pub max_ice_candidates_per_pair: usize,
+    pub ip_hash_salt: String,
+    pub drain_period: Duration,
+    pub reconnect_after: Duration,
+    /// Where sessions are saved for the next server, if anywhere.
+    pub snapshot_path: Option<PathBuf>,
+    pub snapshot_interval: Duration,
+    /// How long after a snapshot was taken its peers can still resume.
+    pub snapshot_restore_window: Duration,
+    /// Becomes true once the server starts shutting down.
+    shutdown: watch::Sender<bool>,
+}
+
+/// The settings that can be reloaded while the server runs.
+pub struct Settings {
+    pub rate_limits: HashMap<String, MessageRateLimit>,
+    pub max_rate_limit_violations: u32,
+    pub ice_servers: Arc<IceServerChain>,
 }
 
-pub type StateType = Arc<Mutex<State>>;
+pub type StateType = Arc<State>;
 
 impl State {
     pub fn new(config: &Config) -> StateType {
-        let base64_engine = base64::engine::GeneralPurpose::new(
-            &base64::alphabet::STANDARD,
-            base64::engine::general_purpose::PAD,
-        );
-        Arc::new(Mutex::new(State {
-            sessions: Default::default(),
-            sharer_socket_addr_to_room: Default::default(),
-            peers: Default::default(),
-            twilio_client: {
-                if let (Some(account_sid), Some(auth_token)) =
-                    (&config.twilio_account_sid, &config.twilio_auth_token)
-                {
-                    Some(twilio::TwilioClient::new(
-                        "https://api.twilio.com",
-                        TwilioAuthentication::BasicAuth {
-                            basic_auth: base64_engine
-                                .encode(format!("{}:{}", account_sid, auth_token).as_bytes()),
-                        },
-                    ))
-                } else {
-                    None
-                }
+        Arc::new(State {
+            connections: Default::default(),
+            rooms: Default::default(),
+            socket_addr_to_peer: Default::default(),
+            peer_rooms: Default::default(),
+            resume_tokens: Default::default(),
+            relayed_peers: Default::default(),
+            cluster: Arc::new(Cluster::new(&config.cluster)),
+            max_viewers_per_room: config.max_viewers_per_room,
+            resume_grace_period: Duration::from_secs(config.resume_grace_period_secs),
+            ping_interval: Duration::from_secs(config.ping_interval_secs),
+            idle_timeout: Duration::from_secs(config.idle_timeout_secs),
+            send_queue_depth: config.send_queue_depth,
+            backpressure_policy: config.backpressure_policy,
+            settings: RwLock::new(Arc::new(Settings {
+                rate_limits: config.rate_limits.clone(),
+                max_rate_limit_violations: config.max_rate_limit_violations,
+                ice_servers: Arc::new(IceServerChain::new(
+                    &config.all_ice_providers(),
+                    config.ice_provider_mode,
+                )),
+            })),
+            ip_rate_limiters: Default::default(),
+            max_message_size: config.max_message_size,
+            payload_limits: PayloadLimits {
+                max_sdp_length: config.max_sdp_length,
+                max_string_length: config.max_string_length,
             },
-            twilio_account_sid: config.twilio_account_sid.clone(),
-        }))
+            max_ice_candidates_per_pair: config.max_ice_candidates_per_pair,
+            ip_hash_salt: config.ip_hash_salt.clone().unwrap_or_default(),
+            drain_period: Duration::from_secs(config.drain_period_secs),
+            reconnect_after: Duration::from_secs(config.reconnect_after_secs),
+            snapshot_path: config.snapshot_path.clone(),
+            snapshot_interval: Duration::from_secs(config.snapshot_interval_secs),
+            snapshot_restore_window: Duration::from_secs(config.snapshot_restore_window_secs),
+            shutdown: watch::channel(false).0,
+        })
     }
 
-    pub fn add_sharer(&mut self, room: String, sender: Tx, socket_addr: SocketAddr) -> Result<()> {
-        if self.sessions.contains_key(&room) {
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Swaps in the reloadable settings of `config`. The ICE providers are kept if their
+    /// configuration did not change, so that they keep their cached credentials.
+    pub fn reload(&self, config: &Config) {
+        let current = self.settings();
+        let providers = config.all_ice_providers();
+        let ice_servers = if current
+            .ice_servers
+            .is_configured_as(&providers, config.ice_provider_mode)
+        {
+            current.ice_servers.clone()
+        } else {
+            let chain = Arc::new(IceServerChain::new(&providers, config.ice_provider_mode));
+            chain.spawn();
+            chain
+        };
+        *self.settings.write().unwrap() = Arc::new(Settings {
+            rate_limits: config.rate_limits.clone(),
+            max_rate_limit_violations: config.max_rate_limit_violations,
+            ice_servers,
+        });
+    }
+
+    pub async fn add_sharer(
+        &self,
+        room: String,
+        peer: Peer,
+        password: Option<&str>,
+        require_approval: bool,
+        max_viewers: Option<usize>,
+    ) -> Result<usize> {
+        let max_viewers = max_viewers
+            .unwrap_or(self.max_viewers_per_room)
+            .min(self.max_viewers_per_room);
+        let password_hash = session::hash_password(password).await?;
+        let mut session = Session::new(room.clone(), max_viewers);
+        session.set_password_hash(password_hash);
+        session.require_approval = require_approval;
+        let Entry::Vacant(entry) = self.rooms.entry(room.clone()) else {
             return Err(format_err!("room already exists"));
-        }
-        self.sessions
-            .insert(room.clone(), Session::new(room.clone(), socket_addr));
-        self.sharer_socket_addr_to_room
-            .insert(socket_addr, room.clone());
+        };
+        self.insert_peer(&mut session, room, peer);
+        entry.insert(Arc::new(Mutex::new(session)));
         metrics::NUM_ONGOING_SESSIONS.inc();
-        self.peers.insert(
-            room.clone(),
-            Peer {
-                room,
-                sender,
-                peer_type: PeerType::Sharer {},
-            },
-        );
-        Ok(())
+        Ok(max_viewers)
     }
 
-    pub fn add_viewer(&mut self, id: String, room: String, sender: Tx) -> Result<()> {
-        if !self.sessions.contains_key(&room) {
-            return Err(format_err!("room does not exist"));
+    pub fn room_exists(&self, room: &String) -> bool {
+        self.rooms.contains_key(room)
+    }
+
+    pub fn room_ids(&self) -> Vec<String> {
+        self.rooms
+            .iter()
+            .map(|session| session.key().clone())
+            .collect()
+    }
+
+    /// Runs `f` with the session of a room locked. Only one session is ever locked at a time,
+    /// and never while holding on to an entry of one of the maps.
+    fn with_session<T>(
+        &self,
+        room: &String,
+        f: impl FnOnce(&mut Session) -> Result<T>,
+    ) -> Result<T> {
+        let room_not_found = || SignallerError::new(ErrorCode::RoomNotFound, "room does not exist");
+        let session = self
+            .rooms
+            .get(room)
+            .map(|session| session.value().clone())
+            .ok_or_else(room_not_found)?;
+        let mut session = lock(&session);
+        // The session may have ended while we waited for it.
+        if session.closed {
+            return Err(room_not_found().into());
+        }
+        f(&mut session)
+    }
+
+    /// Like `with_session`, for the session owned by the given peer. Fails if it is not a
+    /// sharer.
+    fn with_sharer_session<T>(
+        &self,
+        id: &String,
+        f: impl FnOnce(&mut Session) -> Result<T>,
+    ) -> Result<T> {
+        self.check_sharer(id)?;
+        self.with_session(id, f)
+    }
+
+    /// Fails unless the given peer is the sharer of a session.
+    fn check_sharer(&self, id: &String) -> Result<()> {
+        // Sharers are addressed by their room id.
+        let is_sharer = self
+            .peer_rooms
+            .get(id)
+            .is_some_and(|room| room.value() == id);
+        if !is_sharer {
+            return Err(SignallerError::new(
+                ErrorCode::NotSharer,
+                "only the sharer of a session can do this",
+            )
+            .into());
         }
-        self.sessions
-            .get_mut(&room)
-            .unwrap()
-            .viewers
-            .insert(id.clone());
-        self.peers.insert(
-            id,
-            Peer {
-                room,
-                sender,
-                peer_type: PeerType::Viewer {},
-            },
-        );
         Ok(())
     }
 
-    fn remove_session(&mut self, room: &String) {
+    /// The room of a sharer, viewer or waiting viewer.
+    fn room_of(&self, id: &String) -> Option<String> {
+        self.peer_rooms.get(id).map(|room| room.value().clone())
+    }
+
+    /// A snapshot of all sessions, to visit them one by one.
+    fn sessions(&self) -> Vec<Arc<Mutex<Session>>> {
+        self.rooms
+            .iter()
+            .map(|session| session.value().clone())
+            .collect()
+    }
+
+    fn insert_peer(&self, session: &mut Session, id: String, peer: Peer) {
+        if let Some(resume_token) = peer.issued_resume_token() {
+            self.resume_tokens.insert(resume_token, id.clone());
+        }
+        // Peers restored from a snapshot have no connection yet, and those of other nodes
+        // have theirs there.
+        if peer.away_until.is_none() && peer.node.is_none() {
+            self.socket_addr_to_peer
+                .insert(peer.socket_addr, id.clone());
+        }
+        self.peer_rooms.insert(id.clone(), session.sharer.clone());
+        session.peers.insert(id, peer);
+    }
+
+    fn remove_peer(&self, session: &mut Session, id: &String) -> Option<Peer> {
+        let peer = session.peers.remove(id)?;
+        session.forget_ice_candidates(id);
+        self.resume_tokens.remove(&peer.resume_token);
+        self.peer_rooms.remove(id);
+        self.unbind_socket_addr(&peer.socket_addr, id);
+        Some(peer)
+    }
+
+    /// Forgets the socket of a peer unless it already belongs to another one.
+    fn unbind_socket_addr(&self, socket_addr: &SocketAddr, id: &String) {
+        self.socket_addr_to_peer
+            .remove_if(socket_addr, |_, bound| bound == id);
+    }
+
+    /// Removes a viewer that is waiting to be admitted to a room.
+    fn take_pending(&self, session: &mut Session, id: &String) -> Option<PendingJoin> {
+        let pending = session.pending.remove(id)?;
+        self.peer_rooms.remove(id);
+        self.unbind_socket_addr(&pending.peer.socket_addr, id);
+        Some(pending)
+    }
+
+    /// Removes a viewer from its room and lets the sharer know it left.
+    fn remove_viewer(&self, session: &mut Session, id: &String) -> Option<Peer> {
+        if !session.viewers.remove(id) {
+            return None;
+        }
+        let peer = self.remove_peer(session, id)?;
+        session
+            .sharer_peer()
+            .send(&SignallerMessage::Leave { from: id.clone() });
+        session.notify_viewer_count();
+        Some(peer)
+    }
+
+    pub async fn add_viewer(
+        &self,
+        id: String,
+        mut peer: Peer,
+        password: Option<&str>,
+    ) -> Result<JoinOutcome> {
+        let mut verified = None;
+        loop {
+            match self.try_add_viewer(id.clone(), peer, password, verified.as_ref())? {
+                Admission::Done(outcome) => return Ok(outcome),
+                Admission::Verify {
+                    hash,
+                    peer: waiting,
+                } => {
+                    peer = waiting;
+                    let password = password.unwrap_or_default().to_owned();
+                    verified = Some(session::verify_password(hash, password).await?);
+                }
+            }
+        }
+    }
+
+    /// Adds a viewer unless its password still needs to be verified against the hash it
+    /// gets back, outside of the session.
+    fn try_add_viewer(
+        &self,
+        id: String,
+        peer: Peer,
+        password: Option<&str>,
+        verified: Option<&VerifiedPassword>,
+    ) -> Result<Admission> {
+        if self.is_in_session(&id) {
+            return Err(SignallerError::new(
+                ErrorCode::AlreadyInSession,
+                "peer is already part of a session",
+            )
+            .into());
+        }
+        let room = peer.room.clone();
+        self.with_session(&room, |session| {
+            if session.sharer_peer().away_until.is_some() {
+                return Err(SignallerError::new(
+                    ErrorCode::PeerAway,
+                    "the sharer is reconnecting, try again later",
+                )
+                .into());
+            }
+            session.check_banned(&id, &peer)?;
+            session.check_capacity()?;
+            if let Some(hash) = session.check_password(password, verified)? {
+                return Ok(Admission::Verify { hash, peer });
+            }
+            if session.require_approval {
+                self.socket_addr_to_peer
+                    .insert(peer.socket_addr, id.clone());
+                self.peer_rooms.insert(id.clone(), room.clone());
+                session.sharer_peer().send(&SignallerMessage::JoinRequest {
+                    from: id.clone(),
+                    room: room.clone(),
+                });
+                session.pending.insert(
+                    id,
+                    PendingJoin {
+                        peer,
+                        requested_at: Instant::now(),
+                    },
+                );
+                return Ok(Admission::Done(JoinOutcome::Pending));
+            }
+            let resume_token = peer.issued_resume_token();
+            self.admit(session, id, peer);
+            Ok(Admission::Done(JoinOutcome::Joined { resume_token }))
+        })
+    }
+
+    /// Adds a viewer to its session and lets the sharer know it joined.
+    fn admit(&self, session: &mut Session, id: String, peer: Peer) {
+        session.viewers.insert(id.clone());
+        session.sharer_peer().send(&SignallerMessage::Join {
+            from: id.clone(),
+            room: peer.room.clone(),
+            password: None,
+        });
+        self.insert_peer(session, id, peer);
+        session.notify_viewer_count();
+    }
+
+    /// Admits a viewer that is waiting for the approval of the given sharer.
+    pub fn admit_viewer(&self, sharer: &String, viewer: &String) -> Result<()> {
+        self.with_sharer_session(sharer, |session| {
+            let peer = self.take_pending_or_fail(session, viewer)?.peer;
+            if !peer.is_legacy() {
+                peer.send(&SignallerMessage::JoinAccepted {
+                    room: peer.room.clone(),
+                    resume_token: peer.issued_resume_token(),
+                });
+            }
+            self.admit(session, viewer.clone(), peer);
+            Ok(())
+        })
+    }
+
+    /// Declines a viewer that is waiting for the approval of the given sharer.
+    pub fn deny_viewer(&self, sharer: &String, viewer: &String, reason: String) -> Result<()> {
+        self.with_sharer_session(sharer, |session| {
+            let pending = self.take_pending_or_fail(session, viewer)?;
+            pending.peer.send(&SignallerMessage::JoinDeclined {
+                to: viewer.clone(),
+                reason,
+            });
+            Ok(())
+        })
+    }
+
+    fn take_pending_or_fail(&self, session: &mut Session, viewer: &String) -> Result<PendingJoin> {
+        self.take_pending(session, viewer).ok_or_else(|| {
+            SignallerError::new(ErrorCode::PeerNotFound, "no such viewer is waiting").into()
+        })
+    }
+
+    /// Sets or clears the password of the given sharer's session.
+    pub async fn set_password(&self, sharer: &String, password: Option<&str>) -> Result<()> {
+        self.check_sharer(sharer)?;
+        let password_hash = session::hash_password(password).await?;
+        self.with_sharer_session(sharer, |session| {
+            session.set_password_hash(password_hash);
+            Ok(())
+        })
+    }
+
+    /// Removes a viewer, or one waiting to be admitted, from the given sharer's session and
+    /// keeps it from joining again. Banning also keeps out its IP.
+    pub fn kick_viewer(
+        &self,
+        sharer: &String,
+        viewer: &String,
+        reason: Option<String>,
+        ban: bool,
+    ) -> Result<()> {
+        self.with_sharer_session(sharer, |session| {
+            let peer = if let Some(pending) = self.take_pending(session, viewer) {
+                pending.peer
+            } else if session.viewers.remove(viewer) {
+                let peer = self.remove_peer(session, viewer).unwrap();
+                session.notify_viewer_count();
+                peer
+            } else {
+                return Err(SignallerError::new(ErrorCode::PeerNotFound, "no such viewer").into());
+            };
+            session.banned.insert(viewer.clone());
+            if ban {
+                if let Some(hashed_ip) = &peer.hashed_ip {
+                    session.banned.insert(hashed_ip.clone());
+                }
+            }
+            if peer.is_legacy() {
+                // Legacy clients only know how to leave a room that was closed.
+                peer.send(&SignallerMessage::RoomClosed {
+                    to: viewer.clone(),
+                    room: peer.room.clone(),
+                });
+            } else {
+                peer.send(&SignallerMessage::Kicked {
+                    room: peer.room.clone(),
+                    reason,
+                });
+            }
+            Ok(())
+        })
+    }
+
+    /// Declines viewers the sharer did not answer in time.
+    pub fn expire_pending_joins(&self) {
+        for session in self.sessions() {
+            let mut session = lock(&session);
+            let expired: Vec<String> = session
+                .pending
+                .iter()
+                .filter(|(_, pending)| pending.requested_at.elapsed() > PENDING_JOIN_TIMEOUT)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in expired {
+                info!("Join request of {} expired", id);
+                let pending = self.take_pending(&mut session, &id).unwrap();
+                pending.peer.send(&SignallerMessage::JoinDeclined {
+                    to: id.clone(),
+                    reason: ErrorCode::ApprovalTimeout.to_string(),
+                });
+                session
+                    .sharer_peer()
+                    .send(&SignallerMessage::JoinRequestCancelled { from: id });
+            }
+        }
+    }
+
+    pub fn is_in_session(&self, id: &String) -> bool {
+        self.peer_rooms.contains_key(id) || self.relayed_peers.contains_key(id)
+    }
+
+    fn remove_session(&self, session: &mut Session) {
+        let room = session.sharer.clone();
         info!("Removing session {}", room);
-        let session = self.sessions.remove(room).unwrap();
-        self.sharer_socket_addr_to_room
-            .remove(&session.sharer_socket_addr);
-        let duration_sec = session.start_time.elapsed().unwrap().as_secs_f64();
+        self.rooms.remove(&room);
+        self.cluster.release(room.clone());
+        session.closed = true;
+        let duration_sec = session
+            .start_time
+            .elapsed()
+            .unwrap_or_default()
+            .as_secs_f64();
         info!("Ended session with duration: {}s", duration_sec);
         metrics::NUM_ONGOING_SESSIONS.dec();
         metrics::SESSION_DURATION_SEC.observe(duration_sec);
-        for viewer in session.viewers {
-            let _ = self.peers[&viewer].sender.unbounded_send(Message::text(
+        for (id, pending) in std::mem::take(&mut session.pending) {
+            self.peer_rooms.remove(&id);
+            self.unbind_socket_addr(&pending.peer.socket_addr, &id);
+            pending.peer.send(&SignallerMessage::JoinDeclined {
+                to: id,
+                reason: ErrorCode::RoomNotFound.to_string(),
+            });
+        }
+        for viewer in std::mem::take(&mut session.viewers) {
+            let _ = session.peers[&viewer].sender.send(Message::text(
                 serde_json::to_string(&SignallerMessage::RoomClosed {
                     to: viewer.clone(),
                     room: room.clone(),
                 })
                 .unwrap(),
             ));
-            self.peers.remove(&viewer);
+            self.remove_peer(session, &viewer);
         }
-        self.peers.remove(&session.sharer);
+        self.remove_peer(session, &room);
+    }
+
+    /// Ends the session of a room this node no longer owns, telling its sharer as well.
+    pub fn close_room(&self, room: &String) -> Result<()> {
+        self.with_session(room, |session| {
+            session.sharer_peer().send(&SignallerMessage::RoomClosed {
+                to: room.clone(),
+                room: room.clone(),
+            });
+            self.remove_session(session);
+            Ok(())
+        })
     }
 
     /// Leave a session. id is the id of the viewer or the sharer.
-    pub fn leave_session(&mut self, id: String) -> Result<()> {
-        if self.sessions.contains_key(&id) {
+    pub fn leave_session(&self, id: String) -> Result<()> {
+        if self.leave_relayed(&id) {
+            return Ok(());
+        }
+        let room = self
+            .room_of(&id)
+            .ok_or_else(|| SignallerError::new(ErrorCode::PeerNotFound, "peer does not exist"))?;
+        self.with_session(&room, |session| self.leave_locked_session(session, id))
+    }
+
+    fn leave_locked_session(&self, session: &mut Session, id: String) -> Result<()> {
+        if session.sharer == id {
             // id is host. remove session
-            self.remove_session(&id);
+            self.remove_session(session);
+        } else if self.take_pending(session, &id).is_some() {
+            // id is still waiting to be admitted. withdraw the request
+            session
+                .sharer_peer()
+                .send(&SignallerMessage::JoinRequestCancelled { from: id });
         } else {
-            let peer = self
-                .peers
-                .get(&id)
-                .ok_or_else(|| format_err!("Peer does not exist"))?;
-            let session = self.sessions.get_mut(&peer.room).unwrap();
-            session.viewers.remove(&id);
-            self.peers.remove(&id);
+            self.remove_viewer(session, &id).ok_or_else(|| {
+                SignallerError::new(ErrorCode::PeerNotFound, "peer does not exist")
+            })?;
         }
         Ok(())
     }
 
-    pub fn on_disconnect(&mut self, socket_addr: &SocketAddr) {
-        if let Some(room) = self.sharer_socket_addr_to_room.get(socket_addr) {
-            self.remove_session(&room.clone());
+    /// Sends a message from one peer to another in the same session.
+    pub fn forward(&self, from: &String, to: &String, msg: &SignallerMessage) -> Result<()> {
+        let not_in_session = || {
+            SignallerError::new(
+                ErrorCode::NotInSession,
+                "connection is not part of a session",
+            )
+        };
+        if let Some(node) = self.relayed_node(from) {
+            return self.relay(&node, from, msg.clone());
         }
+        let room = self.room_of(from).ok_or_else(not_in_session)?;
+        self.with_session(&room, |session| {
+            // Viewers that were not admitted yet cannot signal anyone.
+            if !session.peers.contains_key(from) {
+                return Err(not_in_session().into());
+            }
+            let peer = session.peers.get(to).ok_or_else(|| {
+                SignallerError::new(ErrorCode::PeerNotFound, "peer does not exist")
+            })?;
+            if peer.away_until.is_some() {
+                return Err(
+                    SignallerError::new(ErrorCode::PeerAway, "peer is reconnecting").into(),
+                );
+            }
+            let payload = Message::text(serde_json::to_string(msg)?);
+            // Losing a candidate only costs a route, so they are the first to go when a peer lags.
+            let sent = if let SignallerMessage::Ice { .. } = msg {
+                peer.sender.send_droppable(payload)
+            } else {
+                peer.sender.send(payload)
+            };
+            // A peer whose queue overflowed is disconnected and about to be marked away.
+            sent.map_err(|_| SignallerError::new(ErrorCode::PeerAway, "peer is not keeping up"))?;
+            Ok(())
+        })
     }
 
-    pub fn get_room_id_from_peer_uuid(&self, viewer_uuid: &String) -> Result<String> {
-        let peer = self
-            .peers
-            .get(viewer_uuid)
-            .ok_or_else(|| format_err!("Peer does not exist"))?;
-        Ok(peer.room.clone())
+    /// Like `forward`, for messages only the sharer of a session may send.
+    pub fn forward_from_sharer(
+        &self,
+        from: &String,
+        to: &String,
+        msg: &SignallerMessage,
+    ) -> Result<()> {
+        self.check_sharer(from)?;
+        self.forward(from, to, msg)
     }
 
-    pub async fn get_ice_servers(&self) -> Vec<IceServer> {
-        if let (Some(client), Some(sid)) = (&self.twilio_client, &self.twilio_account_sid) {
-            get_twilio_ice_servers(client, sid).await
+    pub fn on_connect(&self, socket_addr: SocketAddr, sender: Outbox) {
+        self.connections.insert(
+            socket_addr,
+            ConnectionHandle {
+                sender,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    /// Records that something was received on a connection.
+    pub fn touch(&self, socket_addr: &SocketAddr) {
+        if let Some(mut connection) = self.connections.get_mut(socket_addr) {
+            connection.last_seen = Instant::now();
+        }
+    }
+
+    /// Charges a message of the given type against the limits of its connection and IP.
+    pub fn check_rate_limit(
+        &self,
+        connection: &mut Connection,
+        kind: Option<&str>,
+    ) -> std::result::Result<(), SignallerError> {
+        let settings = self.settings();
+        let Some((kind, limit)) = kind.and_then(|kind| settings.rate_limits.get_key_value(kind))
+        else {
+            return Ok(());
+        };
+        let connection_allowed = limit
+            .per_connection
+            .is_none_or(|rate| connection.rate_limiter.try_take(kind, &rate));
+        let scope = if !connection_allowed {
+            "connection"
+        } else if let Some(rate) = limit.per_ip {
+            let ip_allowed = self
+                .ip_rate_limiters
+                .entry(connection.hashed_ip.clone())
+                .or_default()
+                .try_take(kind, &rate);
+            if ip_allowed {
+                return Ok(());
+            }
+            "ip"
         } else {
-            vec![]
+            return Ok(());
+        };
+        metrics::NUM_RATE_LIMITED_MESSAGES
+            .with_label_values(&[kind, scope])
+            .inc();
+        connection.rate_limit_violations += 1;
+        Err(SignallerError::new(
+            ErrorCode::RateLimited,
+            format!("too many {} messages, slow down", kind),
+        ))
+    }
+
+    /// Counts an ICE candidate sent from one peer to another against the limit for the two.
+    pub fn count_ice_candidate(&self, from: &String, to: &String) -> Result<()> {
+        let Some(room) = self.room_of(from) else {
+            return Ok(());
+        };
+        self.with_session(&room, |session| {
+            // Candidates for peers that are not in the session are not forwarded anyway.
+            if !session.peers.contains_key(to) {
+                return Ok(());
+            }
+            Ok(session.count_ice_candidate(from, to, self.max_ice_candidates_per_pair)?)
+        })
+    }
+
+    /// Forgets the rate limits of IPs that have not sent anything for a while.
+    pub fn prune_rate_limiters(&self) {
+        self.ip_rate_limiters
+            .retain(|_, limiter| !limiter.is_idle());
+    }
+
+    /// Closes connections that stopped responding. Their peers are cleaned up by
+    /// `on_disconnect` once the connection task notices.
+    pub fn reap_idle_connections(&self) {
+        let idle_timeout = self.idle_timeout;
+        self.connections.retain(|socket_addr, connection| {
+            if connection.last_seen.elapsed() <= idle_timeout {
+                return true;
+            }
+            info!("Reaping unresponsive connection {}", socket_addr);
+            metrics::NUM_REAPED_CONNECTIONS.inc();
+            connection.sender.close();
+            false
+        });
+    }
+
+    /// Stops new sessions from starting and tells every connection that the server is going
+    /// away.
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Changes once the server starts shutting down.
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    /// The number of sessions that still have a connected peer.
+    pub fn active_session_count(&self) -> usize {
+        self.sessions()
+            .iter()
+            .filter(|session| {
+                let session = lock(session);
+                session.peers.values().any(|peer| peer.away_until.is_none())
+            })
+            .count()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            taken_at: SystemTime::now(),
+            sessions: self
+                .sessions()
+                .iter()
+                .map(|session| lock(session).to_snapshot())
+                .collect(),
         }
     }
+
+    /// Brings back the sessions of a snapshot with all of their peers away, so that they can
+    /// resume until the restore window since the snapshot ends. Rooms another node owns by
+    /// now are not restored.
+    pub async fn restore(&self, snapshot: Snapshot) {
+        let age = snapshot.taken_at.elapsed().unwrap_or_default();
+        let Some(remaining) = self.snapshot_restore_window.checked_sub(age) else {
+            info!(
+                "Not restoring sessions from a snapshot taken {}s ago",
+                age.as_secs()
+            );
+            return;
+        };
+        let away_until = Instant::now() + remaining;
+        let mut restored_sessions = 0;
+        for snapshot in &snapshot.sessions {
+            // Sessions end with their sharer.
+            if !snapshot.peers.iter().any(|peer| peer.id == snapshot.room) {
+                continue;
+            }
+            if !self.cluster.claim(&snapshot.room).await.unwrap_or(false) {
+                info!(
+                    "Not restoring room {}, this node cannot claim it",
+                    snapshot.room
+                );
+                continue;
+            }
+            let Entry::Vacant(entry) = self.rooms.entry(snapshot.room.clone()) else {
+                continue;
+            };
+            let mut session = Session::from_snapshot(snapshot);
+            for peer in &snapshot.peers {
+                if matches!(peer.peer_type, PeerType::Viewer {}) {
+                    session.viewers.insert(peer.id.clone());
+                }
+                let restored = Peer {
+                    room: snapshot.room.clone(),
+                    sender: outbox::disconnected(),
+                    socket_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+                    peer_type: peer.peer_type,
+                    protocol_version: peer.protocol_version,
+                    hashed_ip: peer.hashed_ip.clone(),
+                    resume_token: peer.resume_token.clone(),
+                    away_until: Some(away_until),
+                    node: None,
+                };
+                self.insert_peer(&mut session, peer.id.clone(), restored);
+            }
+            entry.insert(Arc::new(Mutex::new(session)));
+            metrics::NUM_ONGOING_SESSIONS.inc();
+            restored_sessions += 1;
+        }
+        info!(
+            "Restored {} sessions, their peers can resume for {}s",
+            restored_sessions,
+            remaining.as_secs()
+        );
+    }
+
+    /// Closes every connection once the messages queued for it are written.
+    pub fn close_connections(&self) {
+        for connection in self.connections.iter() {
+            connection
+                .sender
+                .send(Message::close())
+                .unwrap_or_else(|e| info!("Error sending close frame: {}", e));
+            connection.sender.close();
+        }
+    }
+
+    /// Cleans up after the connection of a peer closed. Peers that can resume are kept around
+    /// for the grace period, everyone else leaves right away.
+    pub fn on_disconnect(&self, socket_addr: &SocketAddr) {
+        self.connections.remove(socket_addr);
+        let Some((_, id)) = self.socket_addr_to_peer.remove(socket_addr) else {
+            return;
+        };
+        // Viewers of other nodes cannot resume, since their resume tokens are there.
+        if self.leave_relayed(&id) {
+            return;
+        }
+        let Some(room) = self.room_of(&id) else {
+            return;
+        };
+        let result = self.with_session(&room, |session| {
+            let Some(peer) = session.peers.get_mut(&id) else {
+                // Waiting viewers cannot resume.
+                return self.leave_locked_session(session, id);
+            };
+            // Legacy peers never learn their resume token. Once the server shuts down, peers
+            // can only resume on the next one, from a snapshot.
+            let can_resume = !self.resume_grace_period.is_zero()
+                && !peer.is_legacy()
+                && (!self.is_shutting_down() || self.snapshot_path.is_some());
+            if !can_resume {
+                return self.leave_locked_session(session, id);
+            }
+            info!("{} in room {} is away", id, room);
+            peer.away_until = Some(Instant::now() + self.resume_grace_period);
+            session.broadcast(&id, &SignallerMessage::PeerAway { peer: id.clone() });
+            Ok(())
+        });
+        if let Err(e) = result {
+            info!("Error cleaning up after {}: {}", socket_addr, e);
+        }
+    }
+
+    /// Rebinds the peer a resume token was issued to onto a new connection, returning its id
+    /// and room together with the token that replaces the used one.
+    pub fn resume(&self, token: &String, mut peer: Peer) -> Result<(String, String, String)> {
+        let invalid_token =
+            || SignallerError::new(ErrorCode::InvalidResumeToken, "invalid resume token");
+        let (_, id) = self.resume_tokens.remove(token).ok_or_else(invalid_token)?;
+        let room = self.room_of(&id).ok_or_else(invalid_token)?;
+        self.with_session(&room, |session| {
+            let old = self.remove_peer(session, &id).ok_or_else(invalid_token)?;
+            // The old connection may not have noticed that it is gone yet.
+            old.sender.close();
+            peer.room = old.room.clone();
+            peer.peer_type = old.peer_type;
+            let resume_token = peer.resume_token.clone();
+            self.insert_peer(session, id.clone(), peer);
+            info!("{} resumed in room {}", id, room);
+            session.broadcast(&id, &SignallerMessage::PeerResumed { peer: id.clone() });
+            Ok((id, room.clone(), resume_token))
+        })
+    }
+
+    /// Removes peers that did not come back within the grace period. Sessions end with their
+    /// sharer.
+    pub fn expire_away_peers(&self) {
+        for session in self.sessions() {
+            let mut session = lock(&session);
+            let expired: Vec<String> = session
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.away_until.is_some_and(|t| Instant::now() > t))
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in expired {
+                // The viewers of a session are gone already if its sharer expired first.
+                if session.peers.contains_key(&id) {
+                    info!("{} did not come back", id);
+                    if let Err(e) = self.leave_locked_session(&mut session, id.clone()) {
+                        info!("Error removing {}: {}", id, e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn relayed_node(&self, id: &String) -> Option<String> {
+        self.relayed_peers.get(id).map(|peer| peer.node.clone())
+    }
+
+    fn relay(&self, node: &str, from: &str, message: SignallerMessage) -> Result<()> {
+        self.cluster.send(
+            node,
+            BusMessage::Relay {
+                node: self.cluster.node_id.clone(),
+                from: from.to_string(),
+                message,
+            },
+        )
+    }
+
+    /// Asks the node owning a room to let a viewer of this node join it. The outcome is
+    /// relayed back to the viewer by that node.
+    pub fn relay_join(
+        &self,
+        node: String,
+        id: String,
+        peer: Peer,
+        password: Option<String>,
+    ) -> Result<()> {
+        if self.is_in_session(&id) {
+            return Err(SignallerError::new(
+                ErrorCode::AlreadyInSession,
+                "peer is already part of a session",
+            )
+            .into());
+        }
+        let join_id = self.cluster.next_join_id();
+        self.cluster.send(
+            &node,
+            BusMessage::Join {
+                node: self.cluster.node_id.clone(),
+                join_id,
+                from: id.clone(),
+                room: peer.room.clone(),
+                password,
+                protocol_version: peer.protocol_version,
+                hashed_ip: peer.hashed_ip.clone(),
+            },
+        )?;
+        self.socket_addr_to_peer
+            .insert(peer.socket_addr, id.clone());
+        self.relayed_peers.insert(
+            id,
+            RelayedPeer {
+                node,
+                join_id,
+                sender: peer.sender.clone(),
+                socket_addr: peer.socket_addr,
+                sent_at: Some(Instant::now()),
+            },
+        );
+        Ok(())
+    }
+
+    /// Records that the node owning the room of a relayed viewer got its join.
+    pub fn join_received(&self, node: &String, join_id: u64, id: &String) {
+        if let Some(mut peer) = self.relayed_peers.get_mut(id) {
+            if peer.node == *node && peer.join_id == join_id {
+                peer.sent_at = None;
+            }
+        }
+    }
+
+    /// Declines a relayed viewer whose join never reached the node owning the room, returning
+    /// whether it was still waiting for that.
+    pub fn decline_relayed(&self, node: &String, join_id: u64, id: &String) -> bool {
+        let Some((_, peer)) = self.relayed_peers.remove_if(id, |_, peer| {
+            peer.node == *node && peer.join_id == join_id && peer.sent_at.is_some()
+        }) else {
+            return false;
+        };
+        info!("Node {} did not get the join of {}", node, id);
+        self.unbind_socket_addr(&peer.socket_addr, id);
+        let declined = SignallerMessage::JoinDeclined {
+            to: id.clone(),
+            reason: ErrorCode::Internal.to_string(),
+        };
+        peer.sender
+            .send(Message::text(serde_json::to_string(&declined).unwrap()))
+            .unwrap_or_else(|e| info!("Error declining {}: {}", id, e));
+        true
+    }
+
+    /// Declines relayed viewers whose join the node owning the room did not acknowledge in
+    /// time. That node is told they left, in case only its acknowledgement got lost.
+    pub fn expire_relayed_joins(&self) {
+        let expired: Vec<(String, String, u64)> = self
+            .relayed_peers
+            .iter()
+            .filter(|peer| {
+                peer.sent_at
+                    .is_some_and(|t| t.elapsed() > RELAYED_JOIN_TIMEOUT)
+            })
+            .map(|peer| (peer.key().clone(), peer.node.clone(), peer.join_id))
+            .collect();
+        for (id, node, join_id) in expired {
+            if self.decline_relayed(&node, join_id, &id) {
+                let leave = SignallerMessage::Leave { from: id.clone() };
+                if let Err(e) = self.relay(&node, &id, leave) {
+                    info!("Error relaying that {} left: {}", id, e);
+                }
+            }
+        }
+    }
+
+    /// Lets the node owning the room of a relayed viewer know that it left, returning whether
+    /// it was relayed.
+    fn leave_relayed(&self, id: &String) -> bool {
+        let Some((_, peer)) = self.relayed_peers.remove(id) else {
+            return false;
+        };
+        self.unbind_socket_addr(&peer.socket_addr, id);
+        let leave = SignallerMessage::Leave { from: id.clone() };
+        if let Err(e) = self.relay(&peer.node, id, leave) {
+            info!("Error relaying that {} left: {}", id, e);
+        }
+        true
+    }
+
+    /// Passes on a message the node owning its room sent to a relayed viewer.
+    pub fn deliver(&self, to: &String, payload: String) {
+        let Some(peer) = self.relayed_peers.get(to) else {
+            return;
+        };
+        peer.sender
+            .send(Message::text(payload))
+            .unwrap_or_else(|e| info!("Error delivering message to {}: {}", to, e));
+    }
+
+    /// Forgets a relayed viewer once the node owning its room removed it, unless it joined
+    /// again since.
+    pub fn forget_relayed(&self, node: &String, join_id: u64, id: &String) {
+        if let Some((_, peer)) = self
+            .relayed_peers
+            .remove_if(id, |_, peer| peer.node == *node && peer.join_id == join_id)
+        {
+            self.unbind_socket_addr(&peer.socket_addr, id);
+        }
+    }
+
+    pub fn supported_features(&self) -> Vec<Feature> {
+        let mut features = vec![
+            Feature::TypedErrors,
+            Feature::Passwords,
+            Feature::JoinApproval,
+            Feature::Kick,
+            Feature::MaxViewers,
+        ];
+        if !self.resume_grace_period.is_zero() {
+            features.push(Feature::Resume);
+        }
+        if !self.settings().ice_servers.is_empty() {
+            features.push(Feature::IceServers);
+        }
+        features
+    }
+
+    pub async fn get_ice_servers(&self) -> IceServerList {
+        let ice_servers = self.settings().ice_servers.clone();
+        ice_servers.ice_servers().await
+    }
+}
+
+/// Locks a session. A panic while it was locked does not make it unusable for the rest of the
+/// server.
+fn lock(session: &Mutex<Session>) -> MutexGuard<'_, Session> {
+    session.lock().unwrap_or_else(PoisonError::into_inner)
 }
@@ -1,33 +1,391 @@
-use log::error;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use failure::{format_err, Error};
+use log::{error, info, warn};
+use serde::Deserialize;
 use serde_json::Value;
+use twilio::model::ApiV2010AccountToken;
+use twilio::TwilioAuthentication;
 
-use crate::signaller_message::IceServer;
+use crate::config::OneOrMany;
+use crate::ice_servers::IceServerList;
+use crate::metrics;
+use crate::signaller_message::{RTCIceCredentialType, RTCIceServer};
 
-pub async fn get_twilio_ice_servers(
-    client: &twilio::TwilioClient,
-    account_sid: &str,
-) -> Vec<IceServer> {
-    let response = client.create_token(account_sid).send().await;
-    match response {
-        Ok(token) => token
-            .ice_servers
-            .unwrap_or_default()
-            .iter()
-            .map(|s| match s {
-                Value::Object(s) => {
-                    let url = s.get("url").unwrap().as_str().unwrap().to_owned();
-                    IceServer {
-                        url,
-                        username: token.username.clone().unwrap(),
-                        password: token.password.clone().unwrap(),
+/// Lifetime Twilio gives credentials when the response does not say.
+const DEFAULT_TTL: Duration = Duration::from_secs(86400);
+/// Share of their lifetime after which credentials are refreshed in the background.
+const REFRESH_AFTER: f64 = 0.8;
+/// How long to wait before trying again after Twilio failed. The wait doubles with every
+/// failure in a row, up to `MAX_RETRY_INTERVAL`.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(600);
+/// How long fetching credentials may take before it counts as failed.
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An entry of the `ice_servers` Twilio returns. Older responses only have `url`, newer ones
+/// also have `urls`, which is either one URL or a list of them.
+#[derive(Debug, Deserialize)]
+struct TwilioIceServer {
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    urls: Option<OneOrMany>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    credential: Option<String>,
+}
+
+impl TwilioIceServer {
+    fn urls(&self) -> Vec<String> {
+        match (self.urls.clone(), self.url.clone()) {
+            (Some(urls), _) => urls.into(),
+            (None, Some(url)) => vec![url],
+            (None, None) => vec![],
+        }
+    }
+}
+
+struct Credentials {
+    ice_servers: Vec<RTCIceServer>,
+    fetched_at: Instant,
+    ttl: Duration,
+}
+
+impl Credentials {
+    fn refresh_at(&self) -> Instant {
+        self.fetched_at + self.ttl.mul_f64(REFRESH_AFTER)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.fetched_at.elapsed() >= self.ttl
+    }
+}
+
+/// Twilio's ICE servers, cached for as long as their credentials are valid so that setting up
+/// a call does not wait for Twilio.
+pub struct TwilioIceServers {
+    client: twilio::TwilioClient,
+    account_sid: String,
+    credentials: RwLock<Option<Arc<Credentials>>>,
+    /// Held while refreshing, so that requests arriving before the first credentials were
+    /// fetched can wait for them.
+    refresh_lock: tokio::sync::Mutex<()>,
+}
+
+impl TwilioIceServers {
+    pub fn new(account_sid: &str, auth_token: &str) -> Self {
+        let base64_engine = base64::engine::GeneralPurpose::new(
+            &base64::alphabet::STANDARD,
+            base64::engine::general_purpose::PAD,
+        );
+        TwilioIceServers {
+            client: twilio::TwilioClient::new(
+                "https://api.twilio.com",
+                TwilioAuthentication::BasicAuth {
+                    basic_auth: base64_engine
+                        .encode(format!("{}:{}", account_sid, auth_token).as_bytes()),
+                },
+            ),
+            account_sid: account_sid.to_string(),
+            credentials: RwLock::new(None),
+            refresh_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn cached(&self) -> Option<Arc<Credentials>> {
+        self.credentials.read().unwrap().clone()
+    }
+
+    /// The cached ICE servers. Only `keep_fresh` calls Twilio, so once its credentials
+    /// expired, the last known servers are returned right away while it keeps trying.
+    pub async fn get(&self) -> IceServerList {
+        if let Some(credentials) = self.cached() {
+            if credentials.is_expired() {
+                warn!("Serving expired Twilio ICE servers");
+                return Self::serve(&credentials, "stale");
+            }
+            return Self::serve(&credentials, "hit");
+        }
+        // Nothing was fetched yet, so wait for the attempt that may be running.
+        let _refreshing = self.refresh_lock.lock().await;
+        match self.cached() {
+            Some(credentials) => Self::serve(&credentials, "miss"),
+            None => {
+                metrics::NUM_ICE_SERVER_REQUESTS
+                    .with_label_values(&["unavailable"])
+                    .inc();
+                IceServerList::default()
+            }
+        }
+    }
+
+    fn serve(credentials: &Credentials, result: &str) -> IceServerList {
+        metrics::NUM_ICE_SERVER_REQUESTS
+            .with_label_values(&[result])
+            .inc();
+        let age = credentials.fetched_at.elapsed();
+        metrics::ICE_CREDENTIALS_AGE_SEC.set(age.as_secs() as i64);
+        IceServerList {
+            servers: credentials.ice_servers.clone(),
+            // Clients of expired servers should not ask again before the next attempt to
+            // refresh them.
+            ttl: Some(credentials.ttl.saturating_sub(age).max(RETRY_INTERVAL)),
+        }
+    }
+
+    async fn refresh(&self) -> Result<Arc<Credentials>, Error> {
+        let fetched = tokio::time::timeout(
+            REFRESH_TIMEOUT,
+            get_twilio_ice_servers(&self.client, &self.account_sid),
+        )
+        .await
+        .unwrap_or_else(|_| Err(format_err!("twilio did not answer in time")));
+        match fetched {
+            Ok((ice_servers, ttl)) => {
+                info!("Fetched Twilio ICE servers, valid for {}s", ttl.as_secs());
+                let credentials = Arc::new(Credentials {
+                    ice_servers,
+                    fetched_at: Instant::now(),
+                    ttl,
+                });
+                *self.credentials.write().unwrap() = Some(credentials.clone());
+                metrics::ICE_CREDENTIALS_AGE_SEC.set(0);
+                Ok(credentials)
+            }
+            Err(e) => {
+                error!("Failed to get Twilio ICE servers: {}", e);
+                metrics::NUM_ICE_SERVER_REFRESH_FAILURES.inc();
+                Err(e)
+            }
+        }
+    }
+
+    /// Refreshes the credentials before they expire, for as long as the server runs.
+    pub async fn keep_fresh(self: Arc<Self>) {
+        let mut retry_interval = RETRY_INTERVAL;
+        loop {
+            let next_refresh = {
+                let _refreshing = self.refresh_lock.lock().await;
+                match self.refresh().await {
+                    Ok(credentials) => {
+                        retry_interval = RETRY_INTERVAL;
+                        credentials.refresh_at()
+                    }
+                    Err(_) => {
+                        let next_refresh = Instant::now() + retry_interval;
+                        retry_interval = (retry_interval * 2).min(MAX_RETRY_INTERVAL);
+                        next_refresh
                     }
                 }
-                _ => panic!("Expected object"),
-            })
-            .collect(),
-        Err(e) => {
-            error!("Failed to get Twilio ICE servers: {:?}", e);
-            vec![]
+            };
+            tokio::time::sleep_until(next_refresh.into()).await;
+        }
+    }
+}
+
+async fn get_twilio_ice_servers(
+    client: &twilio::TwilioClient,
+    account_sid: &str,
+) -> Result<(Vec<RTCIceServer>, Duration), Error> {
+    let token = client
+        .create_token(account_sid)
+        .send()
+        .await
+        .map_err(|e| format_err!("{:?}", e))?;
+    Ok(parse_token(token))
+}
+
+/// The ICE servers of a token and how long their credentials are valid.
+fn parse_token(token: ApiV2010AccountToken) -> (Vec<RTCIceServer>, Duration) {
+    let ttl = token
+        .ttl
+        .as_deref()
+        .and_then(|ttl| ttl.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TTL);
+    let ice_servers = parse_ice_servers(
+        token.ice_servers.unwrap_or_default(),
+        token.username.as_deref().unwrap_or_default(),
+        token.password.as_deref().unwrap_or_default(),
+    );
+    (ice_servers, ttl)
+}
+
+/// Turns the entries of a token into ICE servers, skipping those that cannot be used. Entries
+/// without credentials of their own get those of the token.
+fn parse_ice_servers(entries: Vec<Value>, username: &str, password: &str) -> Vec<RTCIceServer> {
+    let mut ice_servers = vec![];
+    for entry in entries {
+        let server = match serde_json::from_value::<TwilioIceServer>(entry.clone()) {
+            Ok(server) => server,
+            Err(e) => {
+                warn!("Skipping malformed Twilio ICE server {}: {}", entry, e);
+                metrics::NUM_MALFORMED_ICE_SERVERS.inc();
+                continue;
+            }
+        };
+        let urls = server.urls();
+        if urls.is_empty() {
+            warn!("Skipping Twilio ICE server without urls: {}", entry);
+            metrics::NUM_MALFORMED_ICE_SERVERS.inc();
+            continue;
+        }
+        let username = server.username.unwrap_or_else(|| username.to_string());
+        let credential = server.credential.unwrap_or_else(|| password.to_string());
+        let has_credentials = !username.is_empty() || !credential.is_empty();
+        ice_servers.push(RTCIceServer {
+            urls,
+            username: Some(username).filter(|_| has_credentials),
+            credential: Some(credential).filter(|_| has_credentials),
+            credential_type: has_credentials.then_some(RTCIceCredentialType::Password),
+        });
+    }
+    ice_servers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Keeps tests from counting each other's malformed servers.
+    static METRICS: Mutex<()> = Mutex::new(());
+
+    /// A token as Twilio returns it, with newer entries having both `url` and `urls`.
+    const TOKEN: &str = r#"{
+        "account_sid": "ACxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx",
+        "date_created": "Fri, 01 Mar 2024 10:00:00 +0000",
+        "date_updated": "Fri, 01 Mar 2024 10:00:00 +0000",
+        "ice_servers": [
+            {
+                "url": "stun:global.stun.twilio.com:3478",
+                "urls": "stun:global.stun.twilio.com:3478"
+            },
+            {
+                "url": "turn:global.turn.twilio.com:3478?transport=udp",
+                "username": "entry-user",
+                "urls": "turn:global.turn.twilio.com:3478?transport=udp",
+                "credential": "entry-credential"
+            },
+            {
+                "url": "turn:global.turn.twilio.com:3478?transport=tcp",
+                "urls": "turn:global.turn.twilio.com:3478?transport=tcp"
+            }
+        ],
+        "password": "token-password",
+        "ttl": "3600",
+        "username": "token-user"
+    }"#;
+
+    fn parse(token: &str) -> (Vec<RTCIceServer>, Duration, u64) {
+        let _metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+        let malformed = metrics::NUM_MALFORMED_ICE_SERVERS.get();
+        let (ice_servers, ttl) = parse_token(serde_json::from_str(token).unwrap());
+        let skipped = metrics::NUM_MALFORMED_ICE_SERVERS.get() - malformed;
+        (ice_servers, ttl, skipped)
+    }
+
+    fn token_with(ice_servers: &str) -> String {
+        format!(
+            r#"{{"ice_servers": {}, "username": "token-user", "password": "token-password"}}"#,
+            ice_servers
+        )
+    }
+
+    fn server(urls: &[&str], username: &str, credential: &str) -> RTCIceServer {
+        RTCIceServer {
+            urls: urls.iter().map(|url| url.to_string()).collect(),
+            username: Some(username.to_string()),
+            credential: Some(credential.to_string()),
+            credential_type: Some(RTCIceCredentialType::Password),
         }
     }
+
+    #[test]
+    fn parses_recorded_token() {
+        let (ice_servers, ttl, skipped) = parse(TOKEN);
+        assert_eq!(ttl, Duration::from_secs(3600));
+        assert_eq!(skipped, 0);
+        assert_eq!(
+            ice_servers,
+            vec![
+                server(
+                    &["stun:global.stun.twilio.com:3478"],
+                    "token-user",
+                    "token-password"
+                ),
+                server(
+                    &["turn:global.turn.twilio.com:3478?transport=udp"],
+                    "entry-user",
+                    "entry-credential"
+                ),
+                server(
+                    &["turn:global.turn.twilio.com:3478?transport=tcp"],
+                    "token-user",
+                    "token-password"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_every_shape_of_urls() {
+        let (ice_servers, _, skipped) = parse(&token_with(
+            r#"[
+                {"url": "stun:legacy.example.com:3478"},
+                {"urls": "turn:one.example.com:3478"},
+                {"urls": ["turn:many.example.com:3478", "turns:many.example.com:5349"]}
+            ]"#,
+        ));
+        assert_eq!(skipped, 0);
+        let urls: Vec<_> = ice_servers.iter().map(|s| s.urls.clone()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                vec!["stun:legacy.example.com:3478".to_string()],
+                vec!["turn:one.example.com:3478".to_string()],
+                vec![
+                    "turn:many.example.com:3478".to_string(),
+                    "turns:many.example.com:5349".to_string()
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_unusable_entries() {
+        let (ice_servers, _, skipped) = parse(&token_with(
+            r#"[
+                "stun:not-an-object.example.com:3478",
+                {"username": "no-url"},
+                {"urls": 3478},
+                {"urls": "turn:kept.example.com:3478", "username": "u", "credential": "c"}
+            ]"#,
+        ));
+        assert_eq!(skipped, 3);
+        assert_eq!(
+            ice_servers,
+            vec![server(&["turn:kept.example.com:3478"], "u", "c")]
+        );
+    }
+
+    #[test]
+    fn defaults_missing_ttl_and_credentials() {
+        let (ice_servers, ttl, skipped) =
+            parse(r#"{"ice_servers": [{"urls": "stun:stun.example.com:3478"}]}"#);
+        assert_eq!(ttl, DEFAULT_TTL);
+        assert_eq!(skipped, 0);
+        assert_eq!(
+            ice_servers,
+            vec![RTCIceServer {
+                urls: vec!["stun:stun.example.com:3478".to_string()],
+                ..Default::default()
+            }]
+        );
+    }
 }